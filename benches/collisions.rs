@@ -0,0 +1,91 @@
+// Lookups on a collision-heavy workload: runs of keys share their home slot, so most lookups probe
+// past other keys first. When the full hashes differ, the stored hashes rule those keys out without
+// comparing them. When they're equal too, every key probed past costs an Eq call.
+//
+// Run with `cargo bench`.
+
+extern crate nonblockinghashmap;
+use nonblockinghashmap::NonBlockingHashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const KEYS: u64 = 4096;
+// Keys with the same home slot
+const RUN: u64 = 8;
+const ROUNDS: usize = 100;
+
+static EQS: AtomicUsize = AtomicUsize::new(0);
+
+struct Key {
+    id: u64,
+    hash: u64,
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        EQS.fetch_add(1, Ordering::Relaxed);
+        self.id == other.id
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+// Hashes a key to the very hash it asks for
+#[derive(Default)]
+struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8) | u64::from(b);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+fn run(name: &str, hash: fn(u64) -> u64) {
+    let map: NonBlockingHashMap<Key, u64, BuildHasherDefault<IdentityHasher>> =
+        NonBlockingHashMap::with_capacity_and_hasher(KEYS as usize, Default::default());
+    let guard = map.guard();
+    for id in 0..KEYS {
+        map.put(Key { id, hash: hash(id) }, id, &guard);
+    }
+    let keys: Vec<Key> = (0..KEYS).map(|id| Key { id, hash: hash(id) }).collect();
+
+    EQS.store(0, Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for key in &keys {
+            black_box(map.get(key, &guard));
+        }
+    }
+    let elapsed = start.elapsed();
+    let lookups = ROUNDS * keys.len();
+    println!(
+        "{:<16} {:>5.2} Eq calls per lookup, {:>6.1} ns per lookup",
+        name,
+        EQS.load(Ordering::Relaxed) as f64 / lookups as f64,
+        elapsed.as_nanos() as f64 / lookups as f64,
+    );
+}
+
+fn main() {
+    // Each run of keys shares the low bits, leaving a gap before the next run's home slot
+    run("distinct hashes", |id| (id / RUN * 2 * RUN) | (id << 32));
+    run("equal hashes", |id| id / RUN * 2 * RUN);
+}
@@ -1,11 +1,11 @@
 extern crate nonblockinghashmap;
 extern crate rand;
-use nonblockinghashmap::{print_all, ConcurrentMap};
+use nonblockinghashmap::{print_all, NonBlockingHashMap};
 use std::sync::Arc;
 use std::thread::spawn;
 
 fn main() {
-    let newmap = ConcurrentMap::with_capacity(1000);
+    let newmap = NonBlockingHashMap::with_capacity(1000);
     let shared_map = Arc::new(newmap);
     let nthreads = 30;
     let put = 1000;
@@ -17,16 +17,18 @@ fn main() {
             let child_map_get = shared_map.clone();
             let writer = spawn(move || {
                 for i in 0..put {
-                    child_map_put
-                        .as_mut()
-                        .put(format!("key {}", i), format!("value {} t {}", i, n));
+                    child_map_put.put(
+                        format!("key {}", i),
+                        format!("value {} t {}", i, n),
+                        &child_map_put.guard(),
+                    );
                 }
             });
 
             let reader = spawn(move || {
                 for i in 0..get {
                     let key = format!("key {}", i % put);
-                    child_map_get.as_mut().get(key);
+                    child_map_get.get(&key, &child_map_get.guard());
                 }
             });
             vec![writer, reader]
@@ -35,5 +37,5 @@ fn main() {
     for t in threads {
         t.join().expect("Error joining");
     }
-    print_all(&Arc::try_unwrap(shared_map).unwrap().as_mut());
+    print_all(&shared_map);
 }
@@ -1,24 +1,32 @@
-use std::intrinsics;
+use std::sync::atomic::{AtomicPtr, Ordering};
 
+#[derive(Debug)]
 pub struct AtomicVec<T> {
-    v: Vec<*mut T>,
+    v: Vec<AtomicPtr<T>>,
 }
 
+// The boxes are read through shared references and freed by whichever thread drops the vector
+unsafe impl<T: Send + Sync> Send for AtomicVec<T> {}
+unsafe impl<T: Send + Sync> Sync for AtomicVec<T> {}
+
 impl<T> AtomicVec<T> {
     pub fn with_capacity(size: usize) -> AtomicVec<T> {
-        AtomicVec { v: vec![std::ptr::null_mut(); size] }
+        AtomicVec { v: (0..size).map(|_| AtomicPtr::new(std::ptr::null_mut())).collect() }
     }
 
     pub fn load(&self, index: usize) -> *mut T {
-        assert!(index < self.v.len());
-        unsafe { intrinsics::atomic_load(self.v.as_ptr().offset(index as isize) as *const usize) as *mut T }
+        self.v[index].load(Ordering::SeqCst)
     }
 
-    pub fn cas(&mut self, index: usize, old: *mut T, val: *mut T) -> *mut T {
-        assert!(index < self.v.len());
-        let (val, _) = unsafe { intrinsics::atomic_cxchg(self.v.as_mut_ptr().offset(index as isize) as *mut usize,
-            old as usize, val as usize) };
-        val as *mut T
+    pub fn cas(&self, index: usize, old: *mut T, val: *mut T) -> *mut T {
+        match self.v[index].compare_exchange(old, val, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(prev) | Err(prev) => prev,
+        }
+    }
+
+    // Plain access for whoever has the vector to itself
+    pub fn get_mut(&mut self, index: usize) -> &mut *mut T {
+        self.v[index].get_mut()
     }
 
     pub fn len(&self) -> usize {
@@ -37,6 +45,7 @@ impl<T> Drop for AtomicVec<T> {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::AtomicVec;
 
@@ -49,13 +58,13 @@ mod tests {
 
     #[test]
     fn test_cas() {
-        let mut v = AtomicVec::with_capacity(100);
+        let v = AtomicVec::with_capacity(100);
         let p = Box::into_raw(Box::new(5));
         assert!(v.cas(10, std::ptr::null_mut(), p).is_null());
 
         let p1 = Box::into_raw(Box::new(42));
         assert_eq!(p, v.cas(10, p, p1));
-        unsafe { Box::from_raw(p) };
+        drop(unsafe { Box::from_raw(p) });
 
         assert_eq!(p1, v.load(10));
         assert!(v.load(11).is_null());
@@ -63,6 +72,6 @@ mod tests {
 
         assert_eq!(p1, v.cas(10, p1, std::ptr::null_mut()));
         assert!(v.cas(10, p1, std::ptr::null_mut()).is_null());
-        unsafe { Box::from_raw(p1) };
+        drop(unsafe { Box::from_raw(p1) });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,327 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
+use std::mem;
+use std::ptr;
+
+use crate::key::{is_absent, is_boxed, KeyHolder, ValueHolder};
+use crate::kvtable::{BoxedKeys, KVs, REPROBE_LIMIT};
+use crate::{box_new_mut_ptr, table_size_for, NonBlockingHashMap, RawMap, MIN_SIZE};
+
+// An entry taken out of a table: its hash, its key box and its value box
+type Moved<K, V> = (u64, *mut KeyHolder<K>, *mut ValueHolder<V>);
+
+// The value of a slot the table gives up, if it has one
+unsafe fn take_value<V>(v: *mut ValueHolder<V>) -> Option<V> {
+    if is_absent(v) {
+        return None;
+    }
+    debug_assert!(is_boxed(v));
+    match *Box::from_raw(v) {
+        ValueHolder::Value(v) => Some(v),
+    }
+}
+
+// A table of len slots with the entries in it, or None if one of them would be further from its
+// home slot than lookups go
+unsafe fn fill_table<K, V>(entries: &[Moved<K, V>], len: usize) -> Option<Box<KVs<BoxedKeys<K>, V>>> {
+    let mut kvs = Box::new(KVs::<BoxedKeys<K>, V>::new(len));
+    'entries: for &(fullhash, k, v) in entries {
+        let mut idx = fullhash as usize & (len - 1);
+        for _ in 0..REPROBE_LIMIT {
+            if (*kvs._ks.get_mut(idx)).is_null() {
+                *kvs._ks.get_mut(idx) = k;
+                *kvs._hashes[idx].get_mut() = fullhash;
+                *kvs._vs.get_mut(idx) = v;
+                continue 'entries;
+            }
+            idx = (idx + 1) & (len - 1);
+        }
+        // The boxes are still the caller's, so take them out again before the table is dropped
+        for idx in 0..len {
+            *kvs._ks.get_mut(idx) = ptr::null_mut();
+            *kvs._vs.get_mut(idx) = ptr::null_mut();
+        }
+        return None;
+    }
+    *kvs._chm._slots.get_mut() = entries.len();
+    Some(kvs)
+}
+
+// ---Exclusive Loading -----------------------------------------------------------------------------
+// A thread with the map to itself fills the newest table directly. No one else reads or writes its
+// slots, so there's nothing to CAS, and growing moves every box into a bigger table at once rather
+// than copying a slot at a time.
+impl<K: Eq + Hash, V: Eq, S: BuildHasher> RawMap<BoxedKeys<K>, V, S> {
+    // Makes room for additional keys, so that putting them doesn't grow the table again
+    fn reserve_exclusive(&mut self, additional: usize) {
+        self.finish_copy();
+        let kvs = unsafe { &mut **self._kvs.get_mut() };
+        let wanted = table_size_for(kvs._chm._slots.get_mut().saturating_add(additional));
+        if wanted > kvs.len() {
+            unsafe { self.grow_exclusive(wanted) };
+        }
+    }
+
+    // Puts the key with its value, and returns the value it replaces if there was one. There must
+    // be no copy under way.
+    fn insert_exclusive(&mut self, key: K, val: V) -> Option<V> {
+        let fullhash = self.hash(&key);
+        loop {
+            let kvs = unsafe { &mut **self._kvs.get_mut() };
+            debug_assert!(kvs._chm.get_newkvs_nonatomic().is_null());
+            let len = kvs.len();
+            let mut idx = fullhash as usize & (len - 1);
+            for _ in 0..REPROBE_LIMIT {
+                let k = *kvs._ks.get_mut(idx);
+                if k.is_null() {
+                    // No fuller than a table sized for the keys, so the map doesn't resize once shared
+                    if (*kvs._chm._slots.get_mut() + 1) << 2 > len {
+                        break;
+                    }
+                    *kvs._ks.get_mut(idx) = box_new_mut_ptr(KeyHolder::Key(key));
+                    *kvs._hashes[idx].get_mut() = fullhash;
+                    *kvs._vs.get_mut(idx) = box_new_mut_ptr(ValueHolder::Value(val));
+                    *kvs._chm._slots.get_mut() += 1;
+                    return None;
+                }
+                if *kvs._hashes[idx].get_mut() == fullhash && unsafe { (*k).key() } == &key {
+                    let oldval = mem::replace(kvs._vs.get_mut(idx), box_new_mut_ptr(ValueHolder::Value(val)));
+                    return unsafe { take_value(oldval) };
+                }
+                idx = (idx + 1) & (len - 1);
+            }
+            unsafe { self.grow_exclusive(len << 1) };
+        }
+    }
+
+    // Moves the keys with a value into a table of at least newlen slots. Keys without one are left
+    // behind, and freed with the old table.
+    unsafe fn grow_exclusive(&mut self, newlen: usize) {
+        let kvs = &mut **self._kvs.get_mut();
+        let mut entries: Vec<Moved<K, V>> = Vec::new();
+        for idx in 0..kvs.len() {
+            if !is_absent(*kvs._vs.get_mut(idx)) {
+                entries.push((
+                    *kvs._hashes[idx].get_mut(),
+                    mem::replace(kvs._ks.get_mut(idx), ptr::null_mut()),
+                    mem::replace(kvs._vs.get_mut(idx), ptr::null_mut()),
+                ));
+            }
+        }
+        let mut newlen = newlen;
+        let newkvs = loop {
+            match fill_table(&entries, newlen) {
+                Some(newkvs) => break newkvs,
+                None => newlen <<= 1,
+            }
+        };
+        drop(Box::from_raw(mem::replace(self._kvs.get_mut(), Box::into_raw(newkvs))));
+    }
+}
+
+// ---Bulk Loader -----------------------------------------------------------------------------------
+/// Fills a map from a single thread before it's shared, about as fast as a plain `HashMap`. No
+/// other thread can see the map until `finish()` hands it over, so entries are written straight
+/// into the slots and the table grows all at once, without the atomic operations and the copying
+/// a shared map needs.
+#[derive(Debug)]
+pub struct BulkLoader<K, V, S = RandomState> {
+    _map: NonBlockingHashMap<K, V, S>,
+    // Keys given a value, added to the count of the map once it's finished
+    _added: usize,
+}
+
+impl<K: Eq + Hash, V: Eq> BulkLoader<K, V> {
+    pub fn new() -> BulkLoader<K, V> {
+        BulkLoader::with_capacity(MIN_SIZE)
+    }
+
+    pub fn with_capacity(initial_sz: usize) -> BulkLoader<K, V> {
+        BulkLoader::with_capacity_and_hasher(initial_sz, RandomState::new())
+    }
+}
+
+impl<K: Eq + Hash, V: Eq, S: BuildHasher + Default> Default for BulkLoader<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K: Eq + Hash, V: Eq, S: BuildHasher> BulkLoader<K, V, S> {
+    pub fn with_hasher(hash_builder: S) -> BulkLoader<K, V, S> {
+        BulkLoader::with_capacity_and_hasher(MIN_SIZE, hash_builder)
+    }
+
+    /// Unlike a map's, the capacity isn't capped: the whole table is made up front
+    pub fn with_capacity_and_hasher(initial_sz: usize, hash_builder: S) -> BulkLoader<K, V, S> {
+        let mut map = NonBlockingHashMap::with_capacity_and_hasher(MIN_SIZE, hash_builder);
+        map._raw.reserve_exclusive(initial_sz);
+        BulkLoader { _map: map, _added: 0 }
+    }
+
+    /// Puts the key with its value, and returns the value it replaces if there was one
+    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+        let oldval = self._map._raw.insert_exclusive(key, val);
+        if oldval.is_none() {
+            self._added += 1;
+        }
+        oldval
+    }
+
+    pub fn len(&self) -> usize {
+        self._added
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self._added == 0
+    }
+
+    /// The map with everything loaded, ready to be shared
+    pub fn finish(self) -> NonBlockingHashMap<K, V, S> {
+        self._map._raw._size.add(self._added as isize);
+        self._map
+    }
+}
+
+impl<K: Eq + Hash, V: Eq, S: BuildHasher> Extend<(K, V)> for BulkLoader<K, V, S> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, val) in iter {
+            self.insert(key, val);
+        }
+    }
+}
+
+// Nothing else can reach a map while it's borrowed mutably, so it's filled like a loader's
+impl<K: Eq + Hash, V: Eq, S: BuildHasher> Extend<(K, V)> for NonBlockingHashMap<K, V, S> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self._raw.reserve_exclusive(iter.size_hint().0);
+        let mut added = 0;
+        for (key, val) in iter {
+            if self._raw.insert_exclusive(key, val).is_none() {
+                added += 1;
+            }
+        }
+        self._raw._size.add(added);
+    }
+}
+
+impl<K: Eq + Hash, V: Eq, S: BuildHasher + Default> FromIterator<(K, V)> for NonBlockingHashMap<K, V, S> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = NonBlockingHashMap::with_hasher(S::default());
+        map.extend(iter);
+        map
+    }
+}
+
+/// Keeps the hasher of the `HashMap`
+impl<K: Eq + Hash, V: Eq, S: BuildHasher + Clone> From<HashMap<K, V, S>> for NonBlockingHashMap<K, V, S> {
+    fn from(entries: HashMap<K, V, S>) -> Self {
+        let mut map = NonBlockingHashMap::with_hasher(entries.hasher().clone());
+        map.extend(entries);
+        map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BulkLoader;
+    use crate::{NonBlockingHashMap, MEMORY_ORDERING};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn test_bulk_loader() {
+        let mut loader = BulkLoader::<u64, u64>::with_capacity(16);
+        assert!(loader.is_empty());
+        for i in 0..10_000 {
+            assert_eq!(loader.insert(i, i), None);
+        }
+        for i in (0..10_000).step_by(2) {
+            assert_eq!(loader.insert(i, i + 1), Some(i));
+        }
+        assert_eq!(loader.len(), 10_000);
+        let map = loader.finish();
+        assert_eq!(map.len(), 10_000);
+        // Left as roomy as a map made for that many keys
+        assert!(map.capacity() >= 40_000);
+        let guard = map.guard();
+        for i in 0..10_000 {
+            assert_eq!(map.get(&i, &guard), Some(&if i % 2 == 0 { i + 1 } else { i }));
+        }
+    }
+
+    // A loader made for its keys never grows
+    #[test]
+    fn test_bulk_loader_capacity() {
+        let mut loader = BulkLoader::<u64, u64>::with_capacity(5000);
+        loader.extend((0..5000).map(|i| (i, i)));
+        assert_eq!(loader.finish().capacity(), 32 * 1024);
+    }
+
+    #[test]
+    fn test_from_iter_and_hashmap() {
+        let map: NonBlockingHashMap<String, usize> = (0..1000).map(|i| (i.to_string(), i)).collect();
+        assert_eq!(map.len(), 1000);
+        let guard = map.guard();
+        assert!((0..1000).all(|i| map.get(i.to_string().as_str(), &guard) == Some(&i)));
+
+        let entries: HashMap<u64, u64> = (0..1000).map(|i| (i, i * 2)).collect();
+        let map = NonBlockingHashMap::from(entries.clone());
+        let copied: HashMap<u64, u64> = map.iter(&map.guard()).map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(copied, entries);
+    }
+
+    // A map which has been shared may be in the middle of a copy, and have removed keys around
+    #[test]
+    fn test_extend_map_in_use() {
+        let mut map = NonBlockingHashMap::<u64, u64>::with_capacity(16);
+        {
+            let guard = map.guard();
+            for i in 0..100 {
+                map.put(i, i, &guard);
+            }
+            for i in (0..100).step_by(3) {
+                map.remove(&i, &guard);
+            }
+            let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+            unsafe { map._raw.resize(kvs) };
+            map.put(1, 0, &guard);
+        }
+        map.extend((50..10_000).map(|i| (i, i + 1)));
+        let guard = map.guard();
+        let expected = (0..50).filter(|i| i % 3 != 0).count() + 9_950;
+        assert_eq!(map.len(), expected);
+        assert_eq!(map.iter(&guard).count(), expected);
+        assert_eq!(map.get(&1, &guard), Some(&0));
+        assert_eq!(map.get(&3, &guard), None);
+        assert!((50..10_000).all(|i| map.get(&i, &guard) == Some(&(i + 1))));
+    }
+
+    // Once finished the map is shared like any other
+    #[test]
+    fn test_bulk_loaded_map_shared() {
+        let nthreads = 4;
+        let map: Arc<NonBlockingHashMap<u64, u64>> = Arc::new((0..10_000).map(|i| (i, i)).collect());
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                spawn(move || {
+                    let guard = map.guard();
+                    for i in (t..20_000).step_by(nthreads as usize) {
+                        map.put(i, i + 1, &guard);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert_eq!(map.len(), 20_000);
+        let guard = map.guard();
+        assert!((0..20_000).all(|i| map.get(&i, &guard) == Some(&(i + 1))));
+    }
+}
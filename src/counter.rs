@@ -0,0 +1,119 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+
+// Enough for the threads of most machines to mostly keep to stripes of their own
+const STRIPES: usize = 32;
+
+// Hands out the stripes round robin, one for each thread as it first adds to a counter
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: Cell<usize> = const { Cell::new(usize::MAX) };
+}
+
+fn stripe_of_thread() -> usize {
+    STRIPE.with(|stripe| {
+        if stripe.get() == usize::MAX {
+            stripe.set(NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES);
+        }
+        stripe.get()
+    })
+}
+
+// A cache line to itself, so adding to one stripe doesn't slow down adding to the next
+#[derive(Debug)]
+#[repr(align(64))]
+struct Stripe(AtomicIsize);
+
+// ---Striped Counter--------------------------------------------------------------------------------
+// A count split over stripes, after the ConcurrentAutoTable of the Java NBHM. Each thread adds to a
+// stripe of its own, so threads counting at once don't fight over a single word. Reading it sums
+// the stripes, which may be off by whatever is added meanwhile.
+#[derive(Debug)]
+pub struct StripedCounter {
+    _stripes: Box<[Stripe]>,
+    // The last sum, and when it was taken in the caller's milliseconds plus one, 0 for never
+    _estimate: AtomicIsize,
+    _estimated_at: AtomicU64,
+}
+
+impl Default for StripedCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StripedCounter {
+    pub fn new() -> StripedCounter {
+        StripedCounter {
+            _stripes: (0..STRIPES).map(|_| Stripe(AtomicIsize::new(0))).collect(),
+            _estimate: AtomicIsize::new(0),
+            _estimated_at: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, delta: isize) {
+        self._stripes[stripe_of_thread()].0.fetch_add(delta, Ordering::SeqCst);
+    }
+
+    pub fn sum(&self) -> isize {
+        self._stripes.iter().map(|s| s.0.load(Ordering::SeqCst)).sum()
+    }
+
+    // The sum taken at most a millisecond before now, so that it's only summed about once a
+    // millisecond however often it's asked for
+    pub fn estimate(&self, now_millis: u64) -> isize {
+        if self._estimated_at.load(Ordering::Relaxed) == now_millis + 1 {
+            return self._estimate.load(Ordering::Relaxed);
+        }
+        let sum = self.sum();
+        self._estimate.store(sum, Ordering::Relaxed);
+        self._estimated_at.store(now_millis + 1, Ordering::Relaxed);
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StripedCounter;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn test_add_and_sum() {
+        let counter = StripedCounter::new();
+        assert_eq!(counter.sum(), 0);
+        counter.add(5);
+        counter.add(-2);
+        assert_eq!(counter.sum(), 3);
+    }
+
+    #[test]
+    fn test_concurrent_add() {
+        let counter = Arc::new(StripedCounter::new());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let counter = counter.clone();
+                spawn(move || {
+                    for _ in 0..10_000 {
+                        counter.add(if t % 2 == 0 { 3 } else { -1 });
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert_eq!(counter.sum(), 4 * 10_000 * 2);
+    }
+
+    #[test]
+    fn test_estimate_is_cached_within_a_millisecond() {
+        let counter = StripedCounter::new();
+        counter.add(1);
+        assert_eq!(counter.estimate(7), 1);
+        counter.add(1);
+        assert_eq!(counter.estimate(7), 1);
+        assert_eq!(counter.estimate(8), 2);
+    }
+}
@@ -0,0 +1,383 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use crate::epoch::Guard;
+use crate::key::{tombstone, Expected, KeyHolder, ValueHolder};
+use crate::kvtable::BoxedKeys;
+use crate::{box_new_mut_ptr, MatchingTypes, RawIter, RawMap, MIN_SIZE};
+
+// Set in a count's state once it has been removed. The rest of the state is the number of adds
+// under way on it.
+const DEAD: u64 = 1 << 63;
+
+// A count in a box of its own. Copying a slot into a new table moves the pointer, not the count,
+// so an add racing the copy lands in the same box whichever table it found it in. A removed count
+// is dead, and an add puts a new box in its place rather than bringing it back. Every count is
+// live until then, 0 included.
+#[derive(Debug)]
+struct Count {
+    _count: AtomicU64,
+    _state: AtomicU64,
+}
+
+impl Count {
+    fn new(count: u64) -> Count {
+        Count {
+            _count: AtomicU64::new(count),
+            _state: AtomicU64::new(0),
+        }
+    }
+
+    // None if the count is dead
+    fn add(&self, delta: u64) -> Option<u64> {
+        if self._state.fetch_add(1, Ordering::SeqCst) & DEAD != 0 {
+            self._state.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        let count = self._count.fetch_add(delta, Ordering::SeqCst).wrapping_add(delta);
+        self._state.fetch_sub(1, Ordering::SeqCst);
+        Some(count)
+    }
+
+    // None if the count is dead
+    fn get(&self) -> Option<u64> {
+        if self._state.load(Ordering::SeqCst) & DEAD != 0 {
+            None
+        } else {
+            Some(self._count.load(Ordering::SeqCst))
+        }
+    }
+
+    // Makes the count dead and returns its final value, or None if it was dead already. Adds which
+    // got in before it died are waited for, each only ever two atomic operations from done.
+    fn kill(&self) -> Option<u64> {
+        if self._state.fetch_or(DEAD, Ordering::SeqCst) & DEAD != 0 {
+            return None;
+        }
+        while self._state.load(Ordering::SeqCst) != DEAD {
+            thread::yield_now();
+        }
+        Some(self._count.load(Ordering::SeqCst))
+    }
+}
+
+// Counts are only ever matched by their slot, so two are only equal if they're the same box
+impl PartialEq for Count {
+    fn eq(&self, other: &Count) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl Eq for Count {}
+
+// ---Counter Map ----------------------------------------------------------------------------------
+/// A map of keys to `u64` counts, for counting from many threads at once. Each key's count is an
+/// atomic integer of its own, so adding to it neither allocates nor replaces the value, and an add
+/// racing a resize isn't lost. Only the first add to a key boxes its key and its count.
+///
+/// Adds wrap around. A key keeps its count until it's removed, even a count of 0, but `get` gives
+/// 0 for a key which has no count as well.
+#[derive(Debug)]
+pub struct CounterMap<K, S = RandomState> {
+    _raw: RawMap<BoxedKeys<K>, Count, S>,
+}
+
+impl<K: Eq + Hash, S: BuildHasher + Default> Default for CounterMap<K, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+/// ```compile_fail
+/// use nonblockinghashmap::CounterMap;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<CounterMap<std::rc::Rc<i32>>>();
+/// ```
+unsafe impl<K: Send + Sync, S: Send> Send for CounterMap<K, S> {}
+
+/// ```compile_fail
+/// use nonblockinghashmap::CounterMap;
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<CounterMap<std::cell::RefCell<i32>>>();
+/// ```
+unsafe impl<K: Send + Sync, S: Sync> Sync for CounterMap<K, S> {}
+
+impl<K: Eq + Hash> CounterMap<K> {
+    pub fn new() -> CounterMap<K> {
+        CounterMap::with_capacity(MIN_SIZE)
+    }
+
+    pub fn with_capacity(initial_sz: usize) -> CounterMap<K> {
+        CounterMap::with_capacity_and_hasher(initial_sz, RandomState::new())
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher> CounterMap<K, S> {
+    pub fn with_hasher(hash_builder: S) -> CounterMap<K, S> {
+        CounterMap::with_capacity_and_hasher(MIN_SIZE, hash_builder)
+    }
+
+    pub fn with_capacity_and_hasher(initial_sz: usize, hash_builder: S) -> CounterMap<K, S> {
+        CounterMap {
+            _raw: RawMap::with_capacity_and_hasher(initial_sz, hash_builder),
+        }
+    }
+
+    /// Pins the map: no key read from it is freed while the guard is held
+    pub fn guard(&self) -> Guard<'_> {
+        self._raw.guard()
+    }
+
+    /// Adds delta to the count of the key, and returns the count it comes to
+    pub fn add(&self, key: &K, delta: u64) -> u64
+    where
+        K: Clone,
+    {
+        let guard = self._raw.guard();
+        loop {
+            unsafe {
+                let table = self._raw.get_table_nonatomic();
+                let curptr = self._raw.get_impl(table, key, &guard).unwrap_or(ptr::null_mut());
+                if !curptr.is_null() {
+                    if let Some(count) = (*curptr).value().add(delta) {
+                        return count;
+                    }
+                }
+                // There's no live count: put a new one in, unless another thread's gets in first
+                let putval = box_new_mut_ptr(ValueHolder::Value(Count::new(delta)));
+                let keyword = if curptr.is_null() {
+                    box_new_mut_ptr(KeyHolder::Key(key.clone()))
+                } else {
+                    ptr::null_mut()
+                };
+                let result = self._raw.put_if_match(
+                    key,
+                    keyword,
+                    putval,
+                    MatchingTypes::MatchValue,
+                    Some(Expected::Slot(curptr)),
+                    &guard,
+                );
+                if result.is_ok() {
+                    return delta;
+                }
+            }
+        }
+    }
+
+    /// The count of the key, 0 if it has none
+    pub fn get<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self._raw.guard();
+        let table = self._raw.get_table_nonatomic();
+        unsafe { self._raw.get_impl(table, key, &guard).and_then(|v| (*v).value().get()).unwrap_or(0) }
+    }
+
+    /// Takes the key out, and returns the count it had. An add racing the removal is either in
+    /// the count returned or in a new count for the key, never lost. The removal waits for adds
+    /// already under way on the count, which are only ever a couple of atomic operations long.
+    pub fn remove<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self._raw.guard();
+        unsafe {
+            let table = self._raw.get_table_nonatomic();
+            let Some(curptr) = self._raw.get_impl(table, key, &guard) else {
+                return 0;
+            };
+            // Once it's dead no add goes into it, so this is its final count
+            let count = (*curptr).value().kill().unwrap_or(0);
+            // Unless an add has put a new count in its place meanwhile
+            let _ = self._raw.put_if_match(
+                key,
+                ptr::null_mut(),
+                tombstone(),
+                MatchingTypes::MatchValue,
+                Some(Expected::Slot(curptr)),
+                &guard,
+            );
+            count
+        }
+    }
+
+    /// Weakly consistent, like `NonBlockingHashMap::iter()`. The guard must come from this map.
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> Counts<'g, K, S> {
+        self._raw.check_guard(guard);
+        Counts {
+            _raw: unsafe { self._raw.iter(guard) },
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self._raw.capacity()
+    }
+
+    /// The number of keys with a count, which may include counts being removed
+    pub fn len(&self) -> usize {
+        self._raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn estimated_len(&self) -> usize {
+        self._raw.estimated_len()
+    }
+}
+
+/// The keys of a counter map and their counts, the keys borrowed for as long as the guard is held
+pub struct Counts<'g, K, S> {
+    _raw: RawIter<'g, BoxedKeys<K>, Count, S>,
+}
+
+impl<'g, K: Eq + Hash, S: BuildHasher> Iterator for Counts<'g, K, S> {
+    type Item = (&'g K, u64);
+
+    fn next(&mut self) -> Option<(&'g K, u64)> {
+        // A count being removed may be dead before it's out of the table
+        for (k, v) in self._raw.by_ref() {
+            if let Some(count) = unsafe { (*v).value().get() } {
+                return Some((unsafe { (*k).key() }, count));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CounterMap;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn test_counter_add_get_remove() {
+        let map = CounterMap::<String>::new();
+        assert_eq!(map.get("a"), 0);
+        assert_eq!(map.remove("a"), 0);
+        assert!(map.is_empty());
+        assert_eq!(map.add(&String::from("a"), 2), 2);
+        assert_eq!(map.add(&String::from("a"), 3), 5);
+        assert_eq!(map.get("a"), 5);
+        assert_eq!(map.remove("a"), 5);
+        assert_eq!(map.remove("a"), 0);
+        assert_eq!(map.get("a"), 0);
+        assert_eq!(map.add(&String::from("a"), 1), 1);
+        assert_eq!(map.len(), 1);
+    }
+
+    // A count of 0 is a count like any other, whether it's added or wrapped round to
+    #[test]
+    fn test_counter_zero() {
+        let map = CounterMap::<u64>::new();
+        assert_eq!(map.add(&1, 0), 0);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.iter(&map.guard()).collect::<Vec<_>>(), vec![(&1, 0)]);
+        map.add(&2, 1);
+        assert_eq!(map.add(&2, u64::MAX), 0);
+        assert_eq!(map.add(&2, 7), 7);
+        assert_eq!(map.len(), 2);
+        let guard = map.guard();
+        let mut counts: Vec<_> = map.iter(&guard).collect();
+        counts.sort();
+        assert_eq!(counts, vec![(&1, 0), (&2, 7)]);
+    }
+
+    // A removed count is dead: an add puts a new count in its place rather than bringing it back
+    #[test]
+    fn test_counter_dead() {
+        let map = CounterMap::<u64>::new();
+        map.add(&1, 3);
+        let guard = map.guard();
+        let table = map._raw.get_table_nonatomic();
+        let count = unsafe { (*map._raw.get_impl(table, &1, &guard).unwrap()).value() };
+        assert_eq!(count.kill(), Some(3));
+        assert_eq!(count.kill(), None);
+        assert_eq!(count.add(1), None);
+        assert_eq!(map.get(&1), 0);
+        assert_eq!(map.iter(&guard).count(), 0);
+        assert_eq!(map.add(&1, 7), 7);
+        assert_eq!(map.get(&1), 7);
+        assert_eq!(count.get(), None);
+    }
+
+    #[test]
+    fn test_counter_resize() {
+        let map = CounterMap::<u64>::with_capacity(16);
+        for i in 0..10_000 {
+            map.add(&i, i + 1);
+        }
+        for i in 0..10_000 {
+            map.add(&i, 1);
+        }
+        assert!(map.capacity() >= 10_000);
+        let counts: HashMap<u64, u64> = map.iter(&map.guard()).map(|(k, c)| (*k, c)).collect();
+        assert_eq!(counts, (0..10_000).map(|i| (i, i + 2)).collect());
+    }
+
+    // Keys are added as the map grows from its smallest size, so most adds race a copy
+    #[test]
+    fn test_counter_concurrent_add_grow() {
+        let nthreads = 8;
+        let num_keys = 5_000;
+        let map = Arc::new(CounterMap::<u64>::with_capacity(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for round in 1..=3 {
+                        for k in 0..num_keys {
+                            map.add(&k, round);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        for k in 0..num_keys {
+            assert_eq!(map.get(&k), nthreads * 6);
+        }
+        assert_eq!(map.len(), num_keys as usize);
+    }
+
+    // Every add goes either into a count taken out by a removal or into the one left at the end
+    #[test]
+    fn test_counter_concurrent_add_remove() {
+        let nthreads = 4;
+        let num_adds = 20_000;
+        let map = Arc::new(CounterMap::<u64>::with_capacity(16));
+        let adders: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..num_adds {
+                        map.add(&(i % 4), 1);
+                    }
+                })
+            })
+            .collect();
+        let remover = {
+            let map = map.clone();
+            spawn(move || (0..num_adds).map(|i| map.remove(&(i % 4))).sum::<u64>())
+        };
+        for t in adders {
+            t.join().expect("Error joining");
+        }
+        let removed = remover.join().expect("Error joining");
+        let left: u64 = (0..4).map(|k| map.get(&k)).sum();
+        assert_eq!(removed + left, nthreads * num_adds);
+    }
+}
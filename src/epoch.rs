@@ -0,0 +1,283 @@
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+
+// Collect garbage every that many retirements
+const COLLECT_INTERVAL: usize = 64;
+
+const INACTIVE: usize = 0;
+
+// ---Epoch-Based Memory Reclamation-----------------------------------------------------------------
+// Everything unlinked from the map is retired to the collector with the epoch it was retired in,
+// and only freed once the epoch has advanced twice since. The epoch can only advance when every
+// pinned guard has seen the current one, so whatever a guard could still reach stays alive until
+// the guard is dropped.
+#[derive(Debug)]
+pub struct Collector {
+    _epoch: AtomicUsize,
+    _participants: AtomicPtr<Participant>,
+    _garbage: AtomicPtr<Retired>,
+    _retired: AtomicUsize,
+    // The epoch of the last collection. Nothing more can be freed until the epoch moves on.
+    _collected_epoch: AtomicUsize,
+}
+
+unsafe impl Send for Collector {}
+unsafe impl Sync for Collector {}
+
+// A pin slot, claimed by one guard at a time. Never freed before the collector.
+struct Participant {
+    // INACTIVE, or the pinned epoch shifted left by one with the lowest bit set
+    _state: AtomicUsize,
+    _next: *mut Participant,
+}
+
+struct Retired {
+    _epoch: usize,
+    _ptr: *mut u8,
+    _free: unsafe fn(*mut u8),
+    _next: *mut Retired,
+}
+
+/// Keeps everything read from the map alive while pinned
+///
+/// A guard pins the thread which made it, so it can't be used from another one:
+///
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMap;
+/// let map = NonBlockingHashMap::<i32, i32>::new();
+/// let guard = map.guard();
+/// std::thread::scope(|s| {
+///     s.spawn(|| map.get(&1, &guard));
+/// });
+/// ```
+pub struct Guard<'a> {
+    _collector: &'a Collector,
+    _participant: *const Participant,
+    _marker: PhantomData<&'a Participant>,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector {
+    pub fn new() -> Collector {
+        Collector {
+            _epoch: AtomicUsize::new(0),
+            _participants: AtomicPtr::new(ptr::null_mut()),
+            _garbage: AtomicPtr::new(ptr::null_mut()),
+            _retired: AtomicUsize::new(0),
+            _collected_epoch: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn pin(&self) -> Guard<'_> {
+        let pinned = (self._epoch.load(Ordering::SeqCst) << 1) | 1;
+        let mut p = self._participants.load(Ordering::SeqCst);
+        while !p.is_null() {
+            let participant = unsafe { &*p };
+            if participant
+                ._state
+                .compare_exchange(INACTIVE, pinned, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                break;
+            }
+            p = participant._next;
+        }
+        if p.is_null() {
+            // Every slot is taken: add one already pinned
+            p = Box::into_raw(Box::new(Participant {
+                _state: AtomicUsize::new(pinned),
+                _next: ptr::null_mut(),
+            }));
+            let mut head = self._participants.load(Ordering::SeqCst);
+            loop {
+                unsafe { (*p)._next = head };
+                match self._participants.compare_exchange(head, p, Ordering::SeqCst, Ordering::SeqCst) {
+                    Ok(_) => break,
+                    Err(current) => head = current,
+                }
+            }
+        }
+        fence(Ordering::SeqCst);
+        Guard {
+            _collector: self,
+            _participant: p,
+            _marker: PhantomData,
+        }
+    }
+
+    // Advances the epoch if all pinned guards are in the current one. Returns the current epoch.
+    fn try_advance(&self) -> usize {
+        let epoch = self._epoch.load(Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        let mut p = self._participants.load(Ordering::SeqCst);
+        while !p.is_null() {
+            let participant = unsafe { &*p };
+            let state = participant._state.load(Ordering::SeqCst);
+            if state != INACTIVE && state != (epoch << 1) | 1 {
+                return epoch;
+            }
+            p = participant._next;
+        }
+        fence(Ordering::SeqCst);
+        match self._epoch.compare_exchange(epoch, epoch.wrapping_add(1), Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => epoch.wrapping_add(1),
+            Err(current) => current,
+        }
+    }
+
+    /// Frees whatever no guard can reach any more
+    pub fn collect(&self) {
+        let epoch = self.try_advance();
+        if self._collected_epoch.swap(epoch, Ordering::SeqCst) == epoch {
+            return;
+        }
+        let mut node = self._garbage.swap(ptr::null_mut(), Ordering::SeqCst);
+        let mut keep: *mut Retired = ptr::null_mut();
+        let mut keep_tail: *mut Retired = ptr::null_mut();
+        while !node.is_null() {
+            let next = unsafe { (*node)._next };
+            if epoch.wrapping_sub(unsafe { (*node)._epoch }) >= 2 {
+                unsafe {
+                    ((*node)._free)((*node)._ptr);
+                    drop(Box::from_raw(node));
+                }
+            } else {
+                unsafe { (*node)._next = keep };
+                if keep.is_null() {
+                    keep_tail = node;
+                }
+                keep = node;
+            }
+            node = next;
+        }
+        if !keep.is_null() {
+            self.push_garbage(keep, keep_tail);
+        }
+    }
+
+    fn push_garbage(&self, first: *mut Retired, last: *mut Retired) {
+        let mut head = self._garbage.load(Ordering::SeqCst);
+        loop {
+            unsafe { (*last)._next = head };
+            match self._garbage.compare_exchange(head, first, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        let mut node = self._garbage.load(Ordering::SeqCst);
+        while !node.is_null() {
+            let retired = unsafe { Box::from_raw(node) };
+            unsafe { (retired._free)(retired._ptr) };
+            node = retired._next;
+        }
+        let mut p = self._participants.load(Ordering::SeqCst);
+        while !p.is_null() {
+            let participant = unsafe { Box::from_raw(p) };
+            assert_eq!(participant._state.load(Ordering::SeqCst), INACTIVE);
+            p = participant._next;
+        }
+    }
+}
+
+impl<'a> Guard<'a> {
+    pub(crate) fn is_from(&self, collector: &Collector) -> bool {
+        ptr::eq(self._collector, collector)
+    }
+
+    /// Has `free(p)` called once no guard can reach `p` any more. `p` must already be unreachable
+    /// for guards pinned from now on.
+    pub(crate) unsafe fn defer(&self, p: *mut u8, free: unsafe fn(*mut u8)) {
+        let node = Box::into_raw(Box::new(Retired {
+            _epoch: self._collector._epoch.load(Ordering::SeqCst),
+            _ptr: p,
+            _free: free,
+            _next: ptr::null_mut(),
+        }));
+        self._collector.push_garbage(node, node);
+        if self._collector._retired.fetch_add(1, Ordering::SeqCst) % COLLECT_INTERVAL == COLLECT_INTERVAL - 1 {
+            self._collector.collect();
+        }
+    }
+
+    /// Drops the box at `p` once no guard can reach it any more
+    pub(crate) unsafe fn defer_drop<T>(&self, p: *mut T) {
+        self.defer(p as *mut u8, drop_box::<T>);
+    }
+}
+
+impl<'a> Drop for Guard<'a> {
+    fn drop(&mut self) {
+        unsafe { (*self._participant)._state.store(INACTIVE, Ordering::SeqCst) };
+    }
+}
+
+unsafe fn drop_box<T>(p: *mut u8) {
+    drop(Box::from_raw(p as *mut T));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Collector;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counted<'a>(&'a AtomicUsize);
+
+    impl<'a> Drop for Counted<'a> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_pin_reuses_participants() {
+        let collector = Collector::new();
+        drop(collector.pin());
+        drop(collector.pin());
+        let g1 = collector.pin();
+        let g2 = collector.pin();
+        assert_ne!(g1._participant, g2._participant);
+    }
+
+    #[test]
+    fn test_pinned_guard_holds_garbage() {
+        let dropped = AtomicUsize::new(0);
+        let collector = Collector::new();
+        let reader = collector.pin();
+        {
+            let guard = collector.pin();
+            unsafe { guard.defer_drop(Box::into_raw(Box::new(Counted(&dropped)))) };
+        }
+        for _ in 0..10 {
+            collector.collect();
+        }
+        assert_eq!(dropped.load(Ordering::SeqCst), 0);
+        drop(reader);
+        for _ in 0..3 {
+            collector.collect();
+        }
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_drop_frees_garbage() {
+        let dropped = AtomicUsize::new(0);
+        let collector = Collector::new();
+        for _ in 0..10 {
+            let guard = collector.pin();
+            unsafe { guard.defer_drop(Box::into_raw(Box::new(Counted(&dropped)))) };
+        }
+        drop(collector);
+        assert_eq!(dropped.load(Ordering::SeqCst), 10);
+    }
+}
@@ -0,0 +1,380 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::ptr;
+
+use crate::key::{inline, inline_bits, is_inline, tombstone, Expected, KeyHolder, ValueHolder, INLINE_BITS};
+use crate::kvtable::BoxedKeys;
+use crate::{box_new_mut_ptr, MatchingTypes, RawMap, MIN_SIZE};
+
+/// A value small enough to be kept in a slot word, which takes at most `INLINE_BITS` bits
+///
+/// `from_bits` must give back the value `to_bits` was called on.
+pub trait Inline: Copy {
+    fn to_bits(self) -> usize;
+    fn from_bits(bits: usize) -> Self;
+}
+
+macro_rules! inline_unsigned {
+    ($($t:ty),*) => {$(
+        impl Inline for $t {
+            fn to_bits(self) -> usize {
+                self as usize
+            }
+
+            fn from_bits(bits: usize) -> Self {
+                bits as $t
+            }
+        }
+    )*};
+}
+
+macro_rules! inline_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl Inline for $t {
+            fn to_bits(self) -> usize {
+                self as $u as usize
+            }
+
+            fn from_bits(bits: usize) -> Self {
+                bits as $u as $t
+            }
+        }
+    )*};
+}
+
+inline_unsigned!(u8, u16);
+inline_signed!(i8 => u8, i16 => u16);
+
+#[cfg(target_pointer_width = "64")]
+inline_unsigned!(u32);
+#[cfg(target_pointer_width = "64")]
+inline_signed!(i32 => u32);
+
+#[cfg(target_pointer_width = "64")]
+impl Inline for f32 {
+    fn to_bits(self) -> usize {
+        f32::to_bits(self) as usize
+    }
+
+    fn from_bits(bits: usize) -> Self {
+        f32::from_bits(bits as u32)
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+impl Inline for char {
+    fn to_bits(self) -> usize {
+        self as usize
+    }
+
+    fn from_bits(bits: usize) -> Self {
+        char::from_u32(bits as u32).expect("not the bits of a char")
+    }
+}
+
+impl Inline for bool {
+    fn to_bits(self) -> usize {
+        self as usize
+    }
+
+    fn from_bits(bits: usize) -> Self {
+        bits != 0
+    }
+}
+
+impl Inline for () {
+    fn to_bits(self) -> usize {
+        0
+    }
+
+    fn from_bits(_bits: usize) -> Self {}
+}
+
+// ---Inline Value Map -------------------------------------------------------------
+/// A map which keeps its values in the value slots themselves rather than in boxes, so neither
+/// puts nor gets allocate for the value. Values are read out by copy, so no guard is needed.
+///
+/// Values are compared by their bits: `compare_and_replace` on a float map tells `0.0` from `-0.0`,
+/// and matches a NaN with the same bits.
+#[derive(Debug)]
+pub struct InlineValueMap<K, V, S = RandomState> {
+    // Every value slot holds an inline word, which is never taken for a box
+    _map: RawMap<BoxedKeys<K>, usize, S>,
+    _marker: PhantomData<V>,
+}
+
+impl<K: Eq + Hash, V: Inline, S: BuildHasher + Default> Default for InlineValueMap<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+/// ```compile_fail
+/// use nonblockinghashmap::InlineValueMap;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<InlineValueMap<std::rc::Rc<u32>, u32>>();
+/// ```
+unsafe impl<K: Send + Sync, V, S: Send> Send for InlineValueMap<K, V, S> {}
+
+/// ```compile_fail
+/// use nonblockinghashmap::InlineValueMap;
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<InlineValueMap<std::cell::RefCell<u32>, u32>>();
+/// ```
+unsafe impl<K: Send + Sync, V, S: Sync> Sync for InlineValueMap<K, V, S> {}
+
+impl<K: Eq + Hash, V: Inline> InlineValueMap<K, V> {
+    pub fn new() -> InlineValueMap<K, V> {
+        InlineValueMap::with_hasher(RandomState::new())
+    }
+
+    pub fn with_capacity(initial_sz: usize) -> InlineValueMap<K, V> {
+        InlineValueMap::with_capacity_and_hasher(initial_sz, RandomState::new())
+    }
+}
+
+impl<K: Eq + Hash, V: Inline, S: BuildHasher> InlineValueMap<K, V, S> {
+    pub fn with_hasher(hash_builder: S) -> InlineValueMap<K, V, S> {
+        InlineValueMap {
+            _map: RawMap::with_capacity_and_hasher(MIN_SIZE, hash_builder),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_capacity_and_hasher(initial_sz: usize, hash_builder: S) -> InlineValueMap<K, V, S> {
+        InlineValueMap {
+            _map: RawMap::with_capacity_and_hasher(initial_sz, hash_builder),
+            _marker: PhantomData,
+        }
+    }
+
+    fn to_word(value: V) -> *mut ValueHolder<usize> {
+        let bits = value.to_bits();
+        assert!(bits >> INLINE_BITS == 0, "value doesn't fit in a slot word");
+        inline(bits)
+    }
+
+    // Empty and TombStone mean there's no value
+    fn from_word(word: *mut ValueHolder<usize>) -> Option<V> {
+        if is_inline(word) {
+            Some(V::from_bits(inline_bits(word)))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the value it replaced, if there was one
+    pub fn put(&self, key: K, newval: V) -> Option<V> {
+        self.put_key_if_match(key, newval, MatchingTypes::MatchAll, None).unwrap_or_else(|v| v)
+    }
+
+    /// Puts only if there is no value for the key. Returns the value which is there otherwise.
+    pub fn put_if_absent(&self, key: K, newval: V) -> Option<V> {
+        self.put_key_if_match(key, newval, MatchingTypes::MatchValue, Some(Expected::Absent))
+            .unwrap_or_else(|v| v)
+    }
+
+    /// Puts only if there is a value for the key already, and returns it
+    pub fn replace<Q>(&self, key: &Q, newval: V) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.put_if_match(key, ptr::null_mut(), Self::to_word(newval), MatchingTypes::MatchAllNotEmpty, None)
+            .ok()
+            .flatten()
+    }
+
+    /// Puts only if the current value has the same bits as expval. Returns Ok with the value
+    /// replaced, or Err with the current value if there is one.
+    pub fn compare_and_replace<Q>(&self, key: &Q, expval: V, newval: V) -> Result<V, Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let expected = Expected::Slot(Self::to_word(expval));
+        match self.put_if_match(key, ptr::null_mut(), Self::to_word(newval), MatchingTypes::MatchValue, Some(expected)) {
+            Ok(oldval) => Ok(oldval.expect("matched a value")),
+            Err(curval) => Err(curval),
+        }
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.put_if_match(key, ptr::null_mut(), tombstone(), MatchingTypes::MatchAll, None).ok().flatten()
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self._map.guard();
+        let table = self._map.get_table_nonatomic();
+        unsafe { self._map.get_impl(table, key, &guard) }.and_then(Self::from_word)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get(key).is_some()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self._map.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self._map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn estimated_len(&self) -> usize {
+        self._map.estimated_len()
+    }
+
+    fn put_key_if_match(
+        &self,
+        key: K,
+        newval: V,
+        matchingtype: MatchingTypes,
+        expval: Option<Expected<usize>>,
+    ) -> Result<Option<V>, Option<V>> {
+        let putval = Self::to_word(newval);
+        let keyptr = box_new_mut_ptr(KeyHolder::Key(key));
+        self.put_if_match(unsafe { (*keyptr).key() }, keyptr, putval, matchingtype, expval)
+    }
+
+    // Translates a put_if_match() result into the values it met
+    fn put_if_match<Q>(
+        &self,
+        key: &Q,
+        keyptr: *mut KeyHolder<K>,
+        putval: *mut ValueHolder<usize>,
+        matchingtype: MatchingTypes,
+        expval: Option<Expected<usize>>,
+    ) -> Result<Option<V>, Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self._map.guard();
+        // Equal values are the same word, which put_if_match() takes for its own put and gives up
+        let matches_putval = expval.as_ref().is_none_or(|e| unsafe { e.matches(putval) });
+        match unsafe { self._map.put_if_match(key, keyptr, putval, matchingtype, expval, &guard) } {
+            Ok(oldval) => Ok(Self::from_word(oldval)),
+            Err(curval) if curval == putval && matches_putval => Ok(Self::from_word(curval)),
+            Err(curval) => Err(Self::from_word(curval)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InlineValueMap;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn test_inline_put_get_remove() {
+        let map = InlineValueMap::<String, u32>::new();
+        assert_eq!(map.put(String::from("a"), 1), None);
+        assert_eq!(map.put(String::from("a"), 2), Some(1));
+        assert_eq!(map.get("a"), Some(2));
+        assert_eq!(map.get("b"), None);
+        assert_eq!(map.remove("a"), Some(2));
+        assert_eq!(map.remove("a"), None);
+        assert!(!map.contains_key("a"));
+        assert_eq!(map.put(String::from("a"), 0), None);
+        assert_eq!(map.get("a"), Some(0));
+    }
+
+    #[test]
+    fn test_inline_conditional_puts() {
+        let map = InlineValueMap::<u64, i16>::new();
+        assert_eq!(map.replace(&1, 5), None);
+        assert_eq!(map.put_if_absent(1, -5), None);
+        assert_eq!(map.put_if_absent(1, 6), Some(-5));
+        assert_eq!(map.replace(&1, 7), Some(-5));
+        assert_eq!(map.compare_and_replace(&1, 8, 9), Err(Some(7)));
+        assert_eq!(map.compare_and_replace(&1, 7, 9), Ok(7));
+        assert_eq!(map.compare_and_replace(&1, 9, 9), Ok(9));
+        assert_eq!(map.replace(&1, 9), Some(9));
+        assert_eq!(map.put(1, 9), Some(9));
+        assert_eq!(map.put_if_absent(1, 9), Some(9));
+        assert_eq!(map.compare_and_replace(&2, 7, 9), Err(None));
+        assert_eq!(map.get(&1), Some(9));
+    }
+
+    #[test]
+    fn test_inline_values_by_bits() {
+        let map = InlineValueMap::<u8, f32>::new();
+        map.put(1, f32::NAN);
+        assert!(map.get(&1).unwrap().is_nan());
+        assert!(map.compare_and_replace(&1, f32::NAN, -0.0).is_ok());
+        assert_eq!(map.compare_and_replace(&1, 0.0, 1.0).unwrap_err().map(f32::to_bits), Some((-0.0f32).to_bits()));
+
+        let chars = InlineValueMap::<u8, char>::new();
+        chars.put(1, '\u{10ffff}');
+        assert_eq!(chars.get(&1), Some('\u{10ffff}'));
+        let flags = InlineValueMap::<u8, bool>::new();
+        flags.put(1, false);
+        assert_eq!(flags.get(&1), Some(false));
+    }
+
+    #[test]
+    fn test_inline_resize() {
+        let map = InlineValueMap::<u64, u32>::with_capacity(16);
+        for i in 0..10_000 {
+            map.put(i, i as u32 * 3);
+        }
+        for i in (0..10_000).step_by(2) {
+            assert_eq!(map.remove(&i), Some(i as u32 * 3));
+        }
+        assert!(map.capacity() >= 10_000);
+        for i in 0..10_000 {
+            assert_eq!(map.get(&i), if i % 2 == 0 { None } else { Some(i as u32 * 3) });
+        }
+    }
+
+    #[test]
+    fn test_inline_concurrent_increments() {
+        let nthreads = 4;
+        let map = Arc::new(InlineValueMap::<u64, u32>::with_capacity(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let map = map.clone();
+                spawn(move || {
+                    for i in 0..1000 {
+                        for k in 0..100 {
+                            map.put_if_absent(k, 0);
+                            let mut cur = map.get(&k).unwrap();
+                            while let Err(Some(v)) = map.compare_and_replace(&k, cur, cur + 1) {
+                                cur = v;
+                            }
+                        }
+                        if i % 100 == 0 {
+                            assert!(map.get(&0).is_some());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        for k in 0..100 {
+            assert_eq!(map.get(&k), Some(nthreads * 1000));
+        }
+    }
+}
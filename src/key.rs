@@ -1,68 +1,157 @@
-//use std::hash::Hash;
+use std::borrow::Borrow;
+use std::ptr;
 
 #[derive(PartialEq, Hash, Debug)]
 pub enum KeyHolder<T> {
     Key(T),
-    Tombstone,
 }
 
-impl<T: PartialEq> KeyHolder<T> {
-    pub fn is_tombstone(&self) -> bool {
-        *self == KeyHolder::Tombstone
+impl<T> KeyHolder<T> {
+    pub fn key(&self) -> &T {
+        match self {
+            KeyHolder::Key(k) => k,
+        }
+    }
+
+    /// Compares with a key in its borrowed form
+    pub fn matches<Q: ?Sized + Eq>(&self, key: &Q) -> bool
+    where
+        T: Borrow<Q>,
+    {
+        self.key().borrow() == key
     }
 }
 
+// Aligned so that the two lowest bits of a pointer to it are free for the Prime and inline tags
 #[derive(PartialEq, Hash, Debug)]
+#[repr(align(4))]
 pub enum ValueHolder<T> {
     Value(T),
-    Tombstone,
-    Prime(Box<ValueHolder<T>>),     // not for direct instantiation
 }
 
 impl<T> ValueHolder<T> {
-    pub fn is_tombstone(&self) -> bool {
+    pub fn value(&self) -> &T {
         match self {
-            ValueHolder::Tombstone => true,
-            ValueHolder::Prime(box ValueHolder::Tombstone) => true,
-            _ => false,
+            ValueHolder::Value(v) => v,
         }
     }
+}
 
-    pub fn is_prime(&self) -> bool {
-        match self {
-            ValueHolder::Prime(_) => true,
-            _ => false,
-        }
-    }
+/// What a `MatchValue` put expects to find in the slot
+pub enum Expected<'a, T> {
+    /// No value: empty, a tombstone or in progress
+    Absent,
+    /// A boxed value equal to this one
+    Value(&'a T),
+    /// The very slot word read before: an inline value, or the box of a value
+    Slot(*mut ValueHolder<T>),
+}
 
-    pub fn value(&self) -> &T {
+impl<'a, T: PartialEq> Expected<'a, T> {
+    /// Must not be given a Prime
+    pub unsafe fn matches(&self, v: *mut ValueHolder<T>) -> bool {
         match self {
-            ValueHolder::Value(v) => v,
-            ValueHolder::Prime(box ValueHolder::Value(v)) => v,
-            _ => panic!("not a prime"),
+            Expected::Absent => is_absent(v),
+            Expected::Value(e) => is_boxed(v) && (*v).value() == *e,
+            // Empty, tombstone and in progress are all the same to whoever read any of them
+            Expected::Slot(p) => v == *p || (is_absent(v) && is_absent(*p)),
         }
     }
+}
 
-    /// Consumes a `Box<Value>` or `Box<Tombstone>`, returning a `Prime`
-    pub fn to_prime(boxed: Box<ValueHolder<T>>) -> ValueHolder<T> {
-        match boxed {
-            box ValueHolder::Value(_) | box ValueHolder::Tombstone => ValueHolder::Prime(boxed),
-            _ => panic!("already a prime"),
-        }
-    }
+// ---Tombstones------------------------------------------------------------------------------------
+// Key and value tombstones are both the address of this static, so no slot needs a box for one.
+// They're only ever compared, and must never be dereferenced or freed. Aligned like ValueHolder.
+static TOMBSTONE: u32 = 0;
 
-    /// Consumes a `Prime`, returning a `Box<Value>` or `Box<Tombstone>`
-    pub fn unwrap_prime(val: ValueHolder<T>) -> Box<ValueHolder<T>> {
-        match val {
-            ValueHolder::Prime(boxed) => boxed,
-            _ => panic!("not a prime"),
-        }
-    }
+pub fn key_tombstone<T>() -> *mut KeyHolder<T> {
+    ptr::addr_of!(TOMBSTONE) as *mut KeyHolder<T>
+}
+
+pub fn tombstone<T>() -> *mut ValueHolder<T> {
+    ptr::addr_of!(TOMBSTONE) as *mut ValueHolder<T>
+}
+
+pub fn is_tombstone<T>(p: *mut ValueHolder<T>) -> bool {
+    p == tombstone()
+}
+
+// What a slot ends up with once it's been copied into the new table
+pub fn tombprime<T>() -> *mut ValueHolder<T> {
+    prime(tombstone())
+}
+
+// ---In Progress-----------------------------------------------------------------------------------
+// What a value slot holds while the value is being computed by the one thread allowed to, so that
+// others wait for it instead of computing one of their own. To anything else it's no value at all.
+// Like the tombstone it's the address of a static, and has a value of its own so it can't share
+// the tombstone's address.
+static IN_PROGRESS: u32 = 1;
+
+pub fn in_progress<T>() -> *mut ValueHolder<T> {
+    ptr::addr_of!(IN_PROGRESS) as *mut ValueHolder<T>
+}
+
+pub fn is_in_progress<T>(p: *mut ValueHolder<T>) -> bool {
+    p == in_progress()
+}
+
+// Empty, a tombstone or in progress: there's no value to read
+pub fn is_absent<T>(p: *mut ValueHolder<T>) -> bool {
+    p.is_null() || is_tombstone(p) || is_in_progress(p)
+}
+
+// ---Primes----------------------------------------------------------------------------------------
+// A Prime is the pointer to the boxed value it wraps with the lowest bit set, so priming a slot
+// neither allocates nor moves the value. A tagged pointer must never be dereferenced.
+const PRIME_TAG: usize = 1;
+
+pub fn prime<T>(p: *mut ValueHolder<T>) -> *mut ValueHolder<T> {
+    p.map_addr(|a| a | PRIME_TAG)
+}
+
+pub fn is_prime<T>(p: *mut ValueHolder<T>) -> bool {
+    p.addr() & PRIME_TAG != 0
+}
+
+pub fn unprime<T>(p: *mut ValueHolder<T>) -> *mut ValueHolder<T> {
+    p.map_addr(|a| a & !PRIME_TAG)
+}
+
+// ---Inline values---------------------------------------------------------------------------------
+// A value small enough is kept in the slot word itself, shifted past the tag bits. Like a Prime it
+// is never dereferenced, and it owns no box. It can be primed like any other value.
+const INLINE_TAG: usize = 2;
+const TAG_BITS: u32 = 2;
+
+/// The most bits an inline value may have
+pub const INLINE_BITS: u32 = usize::BITS - TAG_BITS;
+
+pub fn inline<T>(bits: usize) -> *mut ValueHolder<T> {
+    debug_assert!(bits >> INLINE_BITS == 0);
+    ptr::without_provenance_mut((bits << TAG_BITS) | INLINE_TAG)
+}
+
+pub fn is_inline<T>(p: *mut ValueHolder<T>) -> bool {
+    p.addr() & (INLINE_TAG | PRIME_TAG) == INLINE_TAG
+}
+
+pub fn inline_bits<T>(p: *mut ValueHolder<T>) -> usize {
+    debug_assert!(is_inline(p));
+    p.addr() >> TAG_BITS
+}
+
+// Whether the slot word is a box of the value's own, which whoever takes it out has to free
+pub fn is_boxed<T>(p: *mut ValueHolder<T>) -> bool {
+    !is_absent(p) && p.addr() & (INLINE_TAG | PRIME_TAG) == 0
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{KeyHolder, ValueHolder, ValueHolder::Value, ValueHolder::Tombstone, ValueHolder::Prime};
+    use super::{
+        in_progress, inline, inline_bits, is_absent, is_boxed, is_in_progress, is_inline, is_prime, is_tombstone,
+        key_tombstone, prime, tombprime, tombstone, unprime, Expected, KeyHolder, ValueHolder, ValueHolder::Value,
+    };
 
     #[test]
     fn test_keyholder_key_eq() {
@@ -73,52 +162,96 @@ mod tests {
     }
 
     #[test]
-    fn test_keyholder_is_tombstone() {
-        let k1 = KeyHolder::Key(String::from("abc"));
-        assert!(!k1.is_tombstone());
-        assert!(KeyHolder::<&str>::Tombstone.is_tombstone());
+    fn test_keyholder_matches() {
+        let k = KeyHolder::Key(String::from("abc"));
+        assert!(k.matches("abc"));
+        assert!(!k.matches("abd"));
+    }
+
+    #[test]
+    fn test_prime_tag() {
+        let v = Box::into_raw(Box::new(Value(42u8)));
+        assert!(!is_prime(v));
+        assert!(!is_prime(std::ptr::null_mut::<ValueHolder<u8>>()));
+
+        let primed = prime(v);
+        assert_ne!(primed, v);
+        assert!(is_prime(primed));
+        assert_eq!(unprime(primed), v);
+        assert_eq!(unprime(v), v);
+        assert_eq!(unsafe { (*unprime(primed)).value() }, &42);
+        drop(unsafe { Box::from_raw(v) });
     }
 
     #[test]
-    fn test_keyholder_eq() {
-        let k1 = KeyHolder::Key("abc");
-        assert_ne!(k1, KeyHolder::Tombstone);
-        let k2 = KeyHolder::<i32>::Tombstone;
-        assert_eq!(KeyHolder::Tombstone, k2);
+    fn test_tombstones() {
+        assert!(is_tombstone(tombstone::<String>()));
+        assert!(!is_tombstone(std::ptr::null_mut::<ValueHolder<String>>()));
+        assert!(!is_tombstone(tombprime::<String>()));
+        assert!(is_prime(tombprime::<String>()));
+        assert_eq!(unprime(tombprime::<String>()), tombstone());
+        assert!(!key_tombstone::<String>().is_null());
+
+        let v = Box::into_raw(Box::new(Value(String::from("abc"))));
+        assert!(!is_tombstone(v));
+        assert_ne!(prime(v), tombprime());
+        drop(unsafe { Box::from_raw(v) });
     }
 
     #[test]
-    fn test_valueholder_prime() {
-        assert!(!Value(1).is_prime());
-        assert!(Prime(Box::new(Value("abc"))).is_prime());
-        assert!(!ValueHolder::<usize>::Tombstone.is_prime());
-        assert!(Prime::<usize>(Box::new(Tombstone)).is_prime());
+    fn test_in_progress() {
+        assert!(is_in_progress(in_progress::<String>()));
+        assert_ne!(in_progress::<String>(), tombstone());
+        assert!(is_absent(in_progress::<String>()));
+        assert!(is_absent(tombstone::<String>()));
+        assert!(is_absent(std::ptr::null_mut::<ValueHolder<String>>()));
+        assert!(!is_boxed(in_progress::<String>()));
+        // It's copied into a new table like a value
+        assert_ne!(prime(in_progress::<String>()), tombprime());
+        assert_eq!(unprime(prime(in_progress::<String>())), in_progress());
     }
 
     #[test]
-    fn test_prime() {
-        let v1 = ValueHolder::to_prime(Box::new(ValueHolder::Value(42u64)));
-        assert!(v1.is_prime());
-        assert!(!v1.is_tombstone());
-
-        let boxed1 = ValueHolder::unwrap_prime(v1);
-        assert!(!(*boxed1).is_prime());
-        assert!(!(*boxed1).is_tombstone());
-
-        let v2 = ValueHolder::<usize>::to_prime(Box::new(Tombstone));
-        assert!(v2.is_prime());
-        assert!(v2.is_tombstone());
-
-        let boxed2 = ValueHolder::unwrap_prime(v2);
-        assert!(!(*boxed2).is_prime());
-        assert!((*boxed2).is_tombstone());
+    fn test_inline() {
+        let v = inline::<u64>(42);
+        assert!(is_inline(v));
+        assert!(!is_boxed(v));
+        assert!(!is_prime(v));
+        assert_eq!(inline_bits(v), 42);
+        assert_eq!(inline_bits(inline::<u64>(0)), 0);
+        assert!(!inline::<u64>(0).is_null());
+        assert_eq!(inline_bits(inline::<u64>(usize::MAX >> 2)), usize::MAX >> 2);
+
+        // A primed inline value is told apart from TombPrime, and unprimes to the same word
+        assert!(!is_inline(prime(v)));
+        assert_ne!(prime(v), tombprime());
+        assert_eq!(unprime(prime(v)), v);
+        assert!(!is_inline(tombstone::<u64>()));
+        assert!(!is_inline(std::ptr::null_mut::<ValueHolder<u64>>()));
     }
 
     #[test]
-    fn test_valueholder_tombstone() {
-        assert!(!Value(1).is_tombstone());
-        assert!(!Prime(Box::new(Value("abc"))).is_tombstone());
-        assert!(Tombstone::<usize>.is_tombstone());
-        assert!(Prime::<usize>(Box::new(Tombstone)).is_tombstone());
+    fn test_expected_matches() {
+        let b = Box::into_raw(Box::new(Value(1)));
+        let empty = std::ptr::null_mut();
+        unsafe {
+            assert!(Expected::Value(&1).matches(b));
+            assert!(!Expected::Value(&2).matches(b));
+            assert!(!Expected::Value(&1).matches(tombstone()));
+            assert!(!Expected::Value(&1).matches(empty));
+            assert!(Expected::<usize>::Absent.matches(empty));
+            assert!(Expected::<usize>::Absent.matches(tombstone()));
+            assert!(!Expected::Absent.matches(b));
+            assert!(Expected::Slot(b).matches(b));
+            assert!(Expected::Slot(inline::<usize>(7)).matches(inline(7)));
+            assert!(!Expected::Slot(inline::<usize>(7)).matches(inline(8)));
+            assert!(Expected::<usize>::Slot(empty).matches(tombstone()));
+            assert!(!Expected::Slot(empty).matches(b));
+            assert!(Expected::<usize>::Absent.matches(in_progress()));
+            assert!(Expected::<usize>::Slot(in_progress()).matches(empty));
+            assert!(Expected::<usize>::Slot(empty).matches(in_progress()));
+            assert!(!Expected::Slot(in_progress()).matches(b));
+            drop(Box::from_raw(b));
+        }
     }
-}
\ No newline at end of file
+}
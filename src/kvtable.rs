@@ -1,30 +1,142 @@
 use super::atomicvec::AtomicVec;
-use super::key::{KeyHolder, ValueHolder};
-use std::hash::Hash;
+use super::key::{is_boxed, is_prime, key_tombstone, tombprime, KeyHolder, ValueHolder};
+use std::borrow::Borrow;
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 
 pub static REPROBE_LIMIT: usize = 10;
 
+// ---Key Slots -------------------------------------------------------------------------------------
+// How a table keeps its keys. Each slot holds a word which is empty, a tombstone or a key, and
+// once it has a key it never changes again.
+pub trait KeySlots {
+    type Key;
+    type Word: Copy + Eq;
+
+    // An empty slot, or no key at all
+    const EMPTY: Self::Word;
+
+    fn new(table_size: usize) -> Self;
+    fn len(&self) -> usize;
+    fn load(&self, idx: usize) -> Self::Word;
+    fn cas(&self, idx: usize, old: Self::Word, new: Self::Word) -> Self::Word;
+
+    // What copy_slot() puts into an empty slot, so no fresh key can land there any more
+    fn tombstone() -> Self::Word;
+    // Whether a slot has been tombstoned, so the key must be in a newer table if anywhere
+    fn is_tombstone(word: Self::Word) -> bool;
+
+    // The key in a slot which is neither empty nor a tombstone
+    unsafe fn key(word: &Self::Word) -> &Self::Key;
+
+    // Marks the key as owned by the newer table it has been put into
+    fn set_moved(&self, idx: usize);
+    // Frees a key which never got into any table
+    unsafe fn free(word: Self::Word);
+
+    /// Compares with a key in its borrowed form. A tombstone never matches.
+    unsafe fn matches<Q: ?Sized + Eq>(word: Self::Word, key: &Q) -> bool
+    where
+        Self::Key: Borrow<Q>,
+    {
+        !Self::is_tombstone(word) && Self::key(&word).borrow() == key
+    }
+}
+
+// Keys boxed in KeyHolders. Copying a key into a newer table shares the box with it.
+#[derive(Debug)]
+pub struct BoxedKeys<K> {
+    _ks: AtomicVec<KeyHolder<K>>,
+    // Set once the key in the slot has been put into a newer table, which owns it from then on
+    _moved: Vec<AtomicBool>,
+}
+
+impl<K> KeySlots for BoxedKeys<K> {
+    type Key = K;
+    type Word = *mut KeyHolder<K>;
+
+    const EMPTY: Self::Word = ptr::null_mut();
+
+    fn new(table_size: usize) -> Self {
+        BoxedKeys {
+            _ks: AtomicVec::with_capacity(table_size),
+            _moved: (0..table_size).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self._ks.len()
+    }
+
+    fn load(&self, idx: usize) -> Self::Word {
+        self._ks.load(idx)
+    }
+
+    fn cas(&self, idx: usize, old: Self::Word, new: Self::Word) -> Self::Word {
+        self._ks.cas(idx, old, new)
+    }
+
+    fn tombstone() -> Self::Word {
+        key_tombstone()
+    }
+
+    fn is_tombstone(word: Self::Word) -> bool {
+        word == key_tombstone()
+    }
+
+    unsafe fn key(word: &Self::Word) -> &K {
+        (**word).key()
+    }
+
+    fn set_moved(&self, idx: usize) {
+        self._moved[idx].store(true, Ordering::SeqCst);
+    }
+
+    unsafe fn free(word: Self::Word) {
+        if !word.is_null() {
+            drop(Box::from_raw(word));
+        }
+    }
+}
+
+impl<K> BoxedKeys<K> {
+    // Plain access to a slot of a table no other thread can see
+    pub fn get_mut(&mut self, idx: usize) -> &mut *mut KeyHolder<K> {
+        self._ks.get_mut(idx)
+    }
+}
+
+impl<K> Drop for BoxedKeys<K> {
+    fn drop(&mut self) {
+        // Keys are shared with the new table once copied over, so let the new table free them.
+        for i in 0..self._ks.len() {
+            let k = self._ks.load(i);
+            if self._moved[i].load(Ordering::SeqCst) || k == key_tombstone() {
+                self._ks.cas(i, k, ptr::null_mut());
+            }
+        }
+    }
+}
+
 // ---Hash Table Layer Node -------------------------------------------------------------------------------
-pub struct KVs<K, V> {
-    pub _ks: AtomicVec<KeyHolder<K>>,
+pub struct KVs<KS, V> {
+    pub _ks: KS,
     pub _vs: AtomicVec<ValueHolder<V>>,
-    pub _chm: CHM<K, V>,
-    pub _hashes: Vec<u64>,
+    pub _chm: CHM<KS, V>,
+    pub _hashes: Vec<AtomicU64>,
 }
 
-impl<K: Hash, V> KVs<K, V> {
-    pub fn new(table_size: usize) -> KVs<K, V> {
+impl<KS: KeySlots, V> KVs<KS, V> {
+    pub fn new(table_size: usize) -> KVs<KS, V> {
         KVs {
-            _ks: AtomicVec::with_capacity(table_size),
+            _ks: KS::new(table_size),
             _vs: AtomicVec::with_capacity(table_size),
-            _chm: CHM::<K, V>::new(),
-            _hashes: vec![0; table_size],
+            _chm: CHM::<KS, V>::new(),
+            _hashes: (0..table_size).map(|_| AtomicU64::new(0)).collect(),
         }
     }
 
-    pub fn get_key_nonatomic_at(&self, idx: usize) -> *mut KeyHolder<K> {
+    pub fn get_key_nonatomic_at(&self, idx: usize) -> KS::Word {
         self._ks.load(idx)
     }
 
@@ -32,6 +144,13 @@ impl<K: Hash, V> KVs<K, V> {
         self._vs.load(idx)
     }
 
+    // Tells keys which only share the probe sequence apart without comparing them. The hash is
+    // stored right after the key, so a key without one yet has to be compared.
+    pub fn hash_may_match(&self, idx: usize, fullhash: u64) -> bool {
+        let hash = self._hashes[idx].load(Ordering::SeqCst);
+        hash == 0 || hash == fullhash
+    }
+
     pub fn table_full(&self, reprobe_cnt: usize) -> bool {
         reprobe_cnt >= REPROBE_LIMIT && self._chm._slots.load(Ordering::SeqCst) >= self._ks.len()
     }
@@ -45,22 +164,35 @@ impl<K: Hash, V> KVs<K, V> {
     }
 }
 
+impl<KS, V> Drop for KVs<KS, V> {
+    fn drop(&mut self) {
+        // Only boxes are freed. A finished copy leaves nothing but TombPrime behind.
+        for i in 0..self._vs.len() {
+            let v = self._vs.load(i);
+            if !v.is_null() && !is_boxed(v) {
+                debug_assert!(!is_prime(v) || v == tombprime());
+                self._vs.cas(i, v, ptr::null_mut());
+            }
+        }
+    }
+}
+
 // ---Structure for resizing -------------------------------------------------------
 
-pub struct CHM<K, V> {
-    pub _newkvs: AtomicPtr<KVs<K, V>>,
-    pub _size: AtomicUsize,
+// Named after the CHM class of the original Java NBHM
+#[allow(clippy::upper_case_acronyms)]
+pub struct CHM<KS, V> {
+    pub _newkvs: AtomicPtr<KVs<KS, V>>,
     pub _slots: AtomicUsize,
     pub _copy_done: AtomicUsize,
     pub _copy_idx: AtomicUsize,
     pub _resizer: AtomicUsize,
 }
 
-impl<K, V> CHM<K, V> {
-    pub fn new() -> CHM<K, V> {
+impl<KS, V> CHM<KS, V> {
+    pub fn new() -> CHM<KS, V> {
         CHM {
             _newkvs: AtomicPtr::new(ptr::null_mut()),
-            _size: AtomicUsize::new(0),
             _slots: AtomicUsize::new(0),
             _copy_done: AtomicUsize::new(0),
             _copy_idx: AtomicUsize::new(0),
@@ -69,12 +201,12 @@ impl<K, V> CHM<K, V> {
     }
 
     // FIXME: why "non atomic"?
-    pub fn get_newkvs_nonatomic(&self) -> *mut KVs<K, V> {
+    pub fn get_newkvs_nonatomic(&self) -> *mut KVs<KS, V> {
         self._newkvs.load(Ordering::SeqCst)
     }
 }
 
-impl<K, V> Drop for CHM<K, V> {
+impl<KS, V> Drop for CHM<KS, V> {
     fn drop(&mut self) {
         let p = self._newkvs.load(Ordering::SeqCst);
         if !p.is_null() {
@@ -1,21 +1,41 @@
-#![feature(box_patterns)]
-#![feature(core_intrinsics)]
-
-use std::cell::UnsafeCell;
 use std::cmp::min;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::ptr;
 use std::string::ToString;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
 mod kvtable;
 mod key;
 mod atomicvec;
-
-use crate::key::{KeyHolder, ValueHolder};
-use crate::kvtable::{KVs, REPROBE_LIMIT};
+mod bulk;
+mod counter;
+mod countermap;
+mod epoch;
+mod inline;
+mod long;
+mod set;
+
+use crate::counter::StripedCounter;
+use crate::epoch::Collector;
+use crate::key::{
+    in_progress, inline_bits, is_absent, is_boxed, is_in_progress, is_inline, is_prime, is_tombstone, key_tombstone, prime,
+    tombprime, tombstone, unprime, Expected, KeyHolder, ValueHolder,
+};
+use crate::kvtable::{BoxedKeys, KVs, KeySlots, REPROBE_LIMIT};
+
+pub use crate::bulk::BulkLoader;
+pub use crate::countermap::{CounterMap, Counts};
+pub use crate::epoch::Guard;
+pub use crate::inline::{Inline, InlineValueMap};
+pub use crate::key::INLINE_BITS;
+pub use crate::long::NonBlockingHashMapLong;
+pub use crate::set::{NonBlockingHashSet, SetIter};
 
 const MIN_SIZE_LOG: u32 = 3;
 const MIN_SIZE: usize = 1 << MIN_SIZE_LOG;
@@ -30,59 +50,162 @@ pub enum MatchingTypes {
     FromCopySlot,
 }
 
-// Must be freed with Box::from_raw()
-fn box_new_mut_ptr<T>(v: T) -> *mut T {
-    Box::into_raw(Box::new(v))
+/// What became of a put, and the value it met in the map
+#[derive(Debug, PartialEq)]
+pub enum PutOutcome<'a, V> {
+    /// There was no value for the key, and now there's the new one
+    Inserted,
+    /// The new value took the place of this one
+    Replaced(&'a V),
+    /// The new value didn't match and is dropped. This is the current value, if there is one.
+    Rejected(Option<&'a V>),
 }
 
-#[derive(Debug)]
-pub struct ConcurrentMap<K, V> {
-    inner: UnsafeCell<NonBlockingHashMap<K, V>>,
+impl<'a, V> PutOutcome<'a, V> {
+    // Translates a put_if_match() result
+    unsafe fn from_result(result: Result<*mut ValueHolder<V>, *mut ValueHolder<V>>) -> Self {
+        match result {
+            Ok(oldval) => match value_of(oldval) {
+                None => PutOutcome::Inserted,
+                Some(oldval) => PutOutcome::Replaced(oldval),
+            },
+            Err(curval) => PutOutcome::Rejected(value_of(curval)),
+        }
+    }
 }
 
-unsafe impl<K, V> Sync for ConcurrentMap<K, V> {}
-
-impl<K: Eq + Hash, V: Eq> Default for ConcurrentMap<K, V> {
-    fn default() -> Self {
-        Self::new()
+// Empty, TombStone and InProgress all mean there's no value
+unsafe fn value_of<'a, V>(v: *mut ValueHolder<V>) -> Option<&'a V> {
+    if is_absent(v) {
+        None
+    } else {
+        Some((*v).value())
     }
 }
 
-impl<K: Eq + Hash, V: Eq> ConcurrentMap<K, V> {
-    pub fn new() -> ConcurrentMap<K, V> {
-        ConcurrentMap {
-            inner: UnsafeCell::new(NonBlockingHashMap::new()),
-        }
+// Whether a put may replace v, which must not be a Prime
+unsafe fn is_match<V: PartialEq>(
+    matchingtype: &MatchingTypes,
+    expval: Option<&Expected<V>>,
+    v: *mut ValueHolder<V>,
+) -> bool {
+    match matchingtype {
+        MatchingTypes::MatchAll => true,
+        MatchingTypes::MatchAllNotEmpty => !is_absent(v),
+        // If we expect a TombStone and v is empty, it should be a match.
+        MatchingTypes::MatchValue => expval.unwrap().matches(v),
+        // Only fill in a slot which has never had a value in the new table
+        MatchingTypes::FromCopySlot => v.is_null(),
     }
+}
 
-    pub fn with_capacity(initial_sz: usize) -> ConcurrentMap<K, V> {
-        ConcurrentMap {
-            inner: UnsafeCell::new(NonBlockingHashMap::with_capacity(initial_sz)),
-        }
-    }
+// Must be freed with Box::from_raw()
+fn box_new_mut_ptr<T>(v: T) -> *mut T {
+    Box::into_raw(Box::new(v))
+}
 
-    // "impl DerefMut for ConcurrentMap" won't work because of "deref(&mut self)"
-    #[allow(clippy::mut_from_ref)]
-    pub fn as_mut(&self) -> &mut NonBlockingHashMap<K, V> {
-        unsafe { &mut *self.inner.get() }
+// The size of a table with room for initial_sz keys, which leaves three slots in four free
+fn table_size_for(initial_sz: usize) -> usize {
+    let mut i = MIN_SIZE_LOG;
+    while 1 << i < initial_sz << 2 {
+        i += 1;
     }
+    1 << i
+}
+
+// Frees a retired table. Whatever it has moved on to is still in use.
+unsafe fn free_kvs<KS, V>(p: *mut u8) {
+    let kvs = p as *mut KVs<KS, V>;
+    (*kvs)._chm._newkvs.store(ptr::null_mut(), MEMORY_ORDERING);
+    drop(Box::from_raw(kvs));
 }
 
+
 // ---Hash Map --------------------------------------------------------------------
+/// All operations take &self, so the map can be shared between threads with a plain Arc
+#[derive(Debug)]
+pub struct NonBlockingHashMap<K, V, S = RandomState> {
+    _raw: RawMap<BoxedKeys<K>, V, S>,
+}
+
+// The tables and all that goes with them, whichever way the keys are kept.
+//
+// The table pointers alone would make every map type built on this Send and Sync whatever it
+// holds, so each one implements them itself. Keys are read from any thread and dropped by
+// whichever thread frees the table, and boxed values are read from any thread and dropped by
+// whichever thread collects them once retired, so both must be Send and Sync like in AtomicVec.
+// Keys and values kept in the slot words themselves are only ever copied out of their bits.
 #[derive(Debug)]
-pub struct NonBlockingHashMap<K, V> {
-    _kvs: AtomicPtr<KVs<K, V>>,
+struct RawMap<KS, V, S> {
+    _kvs: AtomicPtr<KVs<KS, V>>,
     //_reprobes: AtomicUint,
-    _last_resize: Instant,
+    _created: Instant,
+    // Milliseconds since _created
+    _last_resize: AtomicU64,
+    _collector: Collector,
+    _hasher: S,
+    // How many keys have a value, whichever table they're in
+    _size: StripedCounter,
 }
 
-impl<K: Eq + Hash, V: Eq> Default for NonBlockingHashMap<K, V> {
+impl<K: Eq + Hash, V: Eq, S: BuildHasher + Default> Default for NonBlockingHashMap<K, V, S> {
     fn default() -> Self {
-        Self::new()
+        Self::with_hasher(S::default())
     }
 }
 
-impl<K, V> Drop for NonBlockingHashMap<K, V> {
+/// A map can only be shared with or sent to other threads if its keys and values can.
+///
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMap;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<NonBlockingHashMap<std::rc::Rc<i32>, i32>>();
+/// ```
+///
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMap;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<NonBlockingHashMap<i32, std::cell::Cell<i32>>>();
+/// ```
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send> Send for NonBlockingHashMap<K, V, S> {}
+
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMap;
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<NonBlockingHashMap<i32, std::rc::Rc<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMap;
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<NonBlockingHashMap<std::cell::RefCell<i32>, i32>>();
+/// ```
+///
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMap;
+/// use std::sync::Arc;
+/// let map = Arc::new(NonBlockingHashMap::<i32, std::cell::Cell<i32>>::new());
+/// let child = map.clone();
+/// std::thread::spawn(move || {
+///     child.get_with(&1, |v| v.get());
+/// });
+/// ```
+///
+/// ```
+/// use nonblockinghashmap::NonBlockingHashMap;
+/// use std::sync::Arc;
+/// let map = Arc::new(NonBlockingHashMap::<String, Vec<i32>>::new());
+/// let child = map.clone();
+/// std::thread::spawn(move || {
+///     child.put(String::from("a"), vec![1], &child.guard());
+/// })
+/// .join()
+/// .unwrap();
+/// assert_eq!(map.get("a", &map.guard()), Some(&vec![1]));
+/// ```
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Sync for NonBlockingHashMap<K, V, S> {}
+
+impl<KS, V, S> Drop for RawMap<KS, V, S> {
     fn drop(&mut self) {
         let p = self._kvs.load(Ordering::SeqCst);
         if !p.is_null() {
@@ -97,23 +220,525 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
     }
 
     pub fn with_capacity(initial_sz: usize) -> NonBlockingHashMap<K, V> {
-        let mut initial_sz = initial_sz;
-        if initial_sz > 1024 * 1024 {
-            initial_sz = 1024 * 1024;
+        NonBlockingHashMap::with_capacity_and_hasher(initial_sz, RandomState::new())
+    }
+}
+
+impl<K: Eq + Hash, V: Eq, S: BuildHasher> NonBlockingHashMap<K, V, S> {
+    pub fn with_hasher(hash_builder: S) -> NonBlockingHashMap<K, V, S> {
+        NonBlockingHashMap::with_capacity_and_hasher(MIN_SIZE, hash_builder)
+    }
+
+    pub fn with_capacity_and_hasher(initial_sz: usize, hash_builder: S) -> NonBlockingHashMap<K, V, S> {
+        NonBlockingHashMap {
+            _raw: RawMap::with_capacity_and_hasher(initial_sz, hash_builder),
+        }
+    }
+
+    /// Pins the map: nothing read from it is freed while the guard is held
+    pub fn guard(&self) -> Guard<'_> {
+        self._raw.guard()
+    }
+
+    pub fn put<'g>(&'g self, key: K, newval: V, guard: &'g Guard) -> PutOutcome<'g, V> {
+        self._raw.check_guard(guard);
+        unsafe {
+            let keyptr = box_new_mut_ptr(KeyHolder::Key(key));
+            let result = self._raw.put_if_match(
+                (*keyptr).key(),
+                keyptr,
+                box_new_mut_ptr(ValueHolder::Value(newval)),
+                MatchingTypes::MatchAll,
+                None,
+                guard,
+            );
+            PutOutcome::from_result(result)
         }
-        let mut i = MIN_SIZE_LOG;
-        while 1 << i < initial_sz << 2 {
-            i += 1;
+    }
+
+    pub fn remove<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self._raw.check_guard(guard);
+        unsafe {
+            // There's nothing to remove unless there's a value, and a value in progress isn't one yet
+            match self._raw.put_if_match(
+                key,
+                ptr::null_mut(),
+                tombstone(),
+                MatchingTypes::MatchAllNotEmpty,
+                None,
+                guard,
+            ) {
+                Ok(oldval) => value_of(oldval),
+                Err(_) => None,
+            }
         }
+    }
 
-        NonBlockingHashMap {
-            _kvs: AtomicPtr::new(box_new_mut_ptr(KVs::<K, V>::new(1 << i))),
+    // Puts only if there is no value for the key. Rejected with the value which won otherwise.
+    pub fn put_if_absent<'g>(&'g self, key: K, newval: V, guard: &'g Guard) -> PutOutcome<'g, V> {
+        self._raw.check_guard(guard);
+        unsafe {
+            let keyptr = box_new_mut_ptr(KeyHolder::Key(key));
+            let result = self._raw.put_if_match(
+                (*keyptr).key(),
+                keyptr,
+                box_new_mut_ptr(ValueHolder::Value(newval)),
+                MatchingTypes::MatchValue,
+                Some(Expected::Absent),
+                guard,
+            );
+            PutOutcome::from_result(result)
+        }
+    }
+
+    // Puts only if there is a value for the key already.
+    pub fn replace<'g, Q>(&'g self, key: &Q, newval: V, guard: &'g Guard) -> PutOutcome<'g, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self._raw.check_guard(guard);
+        unsafe {
+            let result = self._raw.put_if_match(
+                key,
+                ptr::null_mut(),
+                box_new_mut_ptr(ValueHolder::Value(newval)),
+                MatchingTypes::MatchAllNotEmpty,
+                None,
+                guard,
+            );
+            PutOutcome::from_result(result)
+        }
+    }
+
+    // Puts only if the current value equals expval. Rejected with the current value otherwise.
+    pub fn compare_and_replace<'g, Q>(
+        &'g self,
+        key: &Q,
+        expval: &V,
+        newval: V,
+        guard: &'g Guard,
+    ) -> PutOutcome<'g, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self._raw.check_guard(guard);
+        unsafe {
+            let result = self._raw.put_if_match(
+                key,
+                ptr::null_mut(),
+                box_new_mut_ptr(ValueHolder::Value(newval)),
+                MatchingTypes::MatchValue,
+                Some(Expected::Value(expval)),
+                guard,
+            );
+            PutOutcome::from_result(result)
+        }
+    }
+
+    /// Puts what `f` makes of the current value, or removes the key if it makes None, and returns
+    /// what was put.
+    ///
+    /// The put only goes in if the value is still the one `f` was given. If another thread has put
+    /// or removed in between, `f` is called again with the value which got in first, and so on
+    /// until one goes in. So `f` may be called any number of times under contention, and should
+    /// have no other effects; but no other thread's update is ever lost.
+    pub fn compute<'g, F>(&'g self, key: &K, mut f: F, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Clone,
+        F: FnMut(Option<&V>) -> Option<V>,
+    {
+        let new_key = || box_new_mut_ptr(KeyHolder::Key(key.clone()));
+        self.compute_impl(key, new_key, |curval| Some(f(curval)), guard)
+    }
+
+    /// Puts what `f` makes if there's no value for the key, and returns the value which is there
+    /// in the end. Like with `compute()`, `f` may be called more than once, and all but the value
+    /// which gets in are dropped.
+    pub fn compute_if_absent<'g, F>(&'g self, key: &K, mut f: F, guard: &'g Guard) -> &'g V
+    where
+        K: Clone,
+        F: FnMut() -> V,
+    {
+        let new_key = || box_new_mut_ptr(KeyHolder::Key(key.clone()));
+        let put_if_absent = |curval: Option<&V>| match curval {
+            Some(_) => None,
+            None => Some(Some(f())),
+        };
+        self.compute_impl(key, new_key, put_if_absent, guard).expect("either found or put")
+    }
+
+    /// Like `compute()`, but only if there's a value for the key already. Returns None if there
+    /// isn't, or if `f` removes it.
+    pub fn compute_if_present<'g, Q, F>(&'g self, key: &Q, mut f: F, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        F: FnMut(&V) -> Option<V>,
+    {
+        self.compute_impl(key, ptr::null_mut, |curval| curval.map(&mut f), guard)
+    }
+
+    /// Puts value if there's no value for the key, or else what `f` makes of the current value
+    /// and value, removing the key if it makes None. Returns what was put. `f` may be called
+    /// more than once, like with `compute()`.
+    pub fn merge<'g, F>(&'g self, key: &K, value: V, mut f: F, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Clone,
+        V: Clone,
+        F: FnMut(&V, &V) -> Option<V>,
+    {
+        let remap = |curval: Option<&V>| match curval {
+            None => Some(value.clone()),
+            Some(curval) => f(curval, &value),
+        };
+        self.compute(key, remap, guard)
+    }
+
+    // The retry loop of the compute functions. f returns None to leave the value as it is, or the
+    // value to put, None to remove the key. new_key boxes the key if it's put, or gives EMPTY if
+    // it mustn't be. Returns the value left for the key.
+    fn compute_impl<'g, Q, N, F>(&'g self, key: &Q, new_key: N, mut f: F, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        N: Fn() -> *mut KeyHolder<K>,
+        F: FnMut(Option<&'g V>) -> Option<Option<V>>,
+    {
+        self._raw.check_guard(guard);
+        loop {
+            unsafe {
+                let table = self._raw.get_table_nonatomic();
+                let curptr = self._raw.get_impl(table, key, guard).unwrap_or(ptr::null_mut());
+                let curval = value_of(curptr);
+                let putval = match f(curval) {
+                    None => return curval,
+                    Some(Some(newval)) => box_new_mut_ptr(ValueHolder::Value(newval)),
+                    // Nothing to remove
+                    Some(None) if curval.is_none() => return None,
+                    Some(None) => tombstone(),
+                };
+                let keyword = if curval.is_none() { new_key() } else { ptr::null_mut() };
+                // The box of a value is never reused while the guard is held, so it's the very
+                // value f was given if it matches
+                let result = self._raw.put_if_match(
+                    key,
+                    keyword,
+                    putval,
+                    MatchingTypes::MatchValue,
+                    Some(Expected::Slot(curptr)),
+                    guard,
+                );
+                if result.is_ok() {
+                    return value_of(putval);
+                }
+            }
+        }
+    }
+
+    /// Returns the value for the key, putting what `f` makes if there's none. Threads getting here
+    /// at once for the same key may each call their own `f`, but only the value which gets in
+    /// first is kept, and all of them return it.
+    pub fn get_or_insert_with<'g, F>(&'g self, key: &K, f: F, guard: &'g Guard) -> &'g V
+    where
+        K: Clone,
+        F: FnOnce() -> V,
+    {
+        if let Some(v) = self.get(key, guard) {
+            return v;
+        }
+        unsafe {
+            let putval = box_new_mut_ptr(ValueHolder::Value(f()));
+            let keyptr = box_new_mut_ptr(KeyHolder::Key(key.clone()));
+            match self._raw.put_if_match(
+                key,
+                keyptr,
+                putval,
+                MatchingTypes::MatchValue,
+                Some(Expected::Absent),
+                guard,
+            ) {
+                Ok(_) => (*putval).value(),
+                Err(curval) => value_of(curval).expect("only a value beats an absent one"),
+            }
+        }
+    }
+
+    /// Like `get_or_insert_with()`, but `f` is only called by one thread at a time for the key. The
+    /// first thread to get here marks the value as in progress, and the others wait for it to put
+    /// the value instead of computing one of their own. If `f` panics, the mark is taken out
+    /// again and one of the waiting threads computes the value instead.
+    ///
+    /// To every other operation, the key has no value while it's in progress. So a put meanwhile
+    /// goes ahead, and wins over the computed value.
+    ///
+    /// A waiting thread checks on the value again after yielding a few times, then after sleeping
+    /// twice as long each time up to about a millisecond. So it takes no core away from `f` for
+    /// long, but may see the value up to a millisecond after it's put.
+    pub fn get_or_insert_with_once<'g, F>(&'g self, key: &K, f: F, guard: &'g Guard) -> &'g V
+    where
+        K: Clone,
+        F: FnOnce() -> V,
+    {
+        if let Some(v) = self.get(key, guard) {
+            return v;
+        }
+        // The key is only boxed once it's known not to be in the table
+        let mut put_key = false;
+        let mut waits = 0;
+        loop {
+            let keyword = if put_key { box_new_mut_ptr(KeyHolder::Key(key.clone())) } else { ptr::null_mut() };
+            let result = unsafe {
+                self._raw.put_if_match(
+                    key,
+                    keyword,
+                    in_progress(),
+                    MatchingTypes::MatchValue,
+                    Some(Expected::Absent),
+                    guard,
+                )
+            };
+            match result {
+                Ok(_) => break,
+                Err(curval) if curval.is_null() => put_key = true,
+                Err(curval) if is_in_progress(curval) => {
+                    wait_in_progress(waits);
+                    waits += 1;
+                }
+                Err(curval) => return unsafe { (*curval).value() },
+            }
+        }
+
+        let abandoned = Abandoned {
+            _map: self,
+            _key: key,
+            _guard: guard,
+        };
+        let putval = box_new_mut_ptr(ValueHolder::Value(f()));
+        mem::forget(abandoned);
+        unsafe {
+            // The key is put again if a put and a removal meanwhile have left it behind in an older
+            // table
+            let keyptr = box_new_mut_ptr(KeyHolder::Key(key.clone()));
+            match self._raw.put_if_match(
+                key,
+                keyptr,
+                putval,
+                MatchingTypes::MatchValue,
+                Some(Expected::Slot(in_progress())),
+                guard,
+            ) {
+                Ok(_) => (*putval).value(),
+                Err(curval) => value_of(curval).expect("only a value beats an absent one"),
+            }
+        }
+    }
+
+    /// The value stays readable as long as the guard is held, even if it's replaced or removed
+    /// in the meantime. The guard must come from this map.
+    pub fn get<'g, Q>(&'g self, key: &Q, guard: &'g Guard) -> Option<&'g V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self._raw.check_guard(guard);
+        let table = self._raw.get_table_nonatomic();
+        let maybe_val = unsafe { self._raw.get_impl(table, key, guard) };
+        maybe_val.map(|v| unsafe { (*v).value() })
+    }
+
+    /// Calls f with the value for the key, if there's one, and returns what it returns
+    pub fn get_with<Q, R, F>(&self, key: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        F: FnOnce(&V) -> R,
+    {
+        let guard = self._raw.guard();
+        self.get(key, &guard).map(f)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self._raw.guard();
+        self.get(key, &guard).is_some()
+    }
+
+    pub fn get_table_nonatomic(&self) -> *mut KVs<BoxedKeys<K>, V> {
+        self._raw.get_table_nonatomic()
+    }
+
+    pub fn get_kvs_level(&self, level: u32) -> Option<*mut KVs<BoxedKeys<K>, V>> {
+        self._raw.get_kvs_level(level)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self._raw.capacity()
+    }
+
+    /// The number of keys with a value. Puts and removes under way on other threads may or may not
+    /// be counted yet.
+    pub fn len(&self) -> usize {
+        self._raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Like `len()`, but counted at most once a millisecond however often it's asked for, so it may
+    /// be that much behind
+    pub fn estimated_len(&self) -> usize {
+        self._raw.estimated_len()
+    }
+
+    /// Weakly consistent, like the iterators of Java's concurrent maps: every key in the map for
+    /// the whole walk is yielded exactly once, with its value as of when it's reached. Keys put or
+    /// removed meanwhile may or may not be, but no key is yielded twice and no removed value is
+    /// yielded after its removal. The guard must come from this map.
+    ///
+    /// The walk goes through the table bucket by bucket, like `scan()`. A resize in progress is
+    /// only helped along by copying the slots of each bucket as the walk gets to it.
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> Iter<'g, K, V, S> {
+        self._raw.check_guard(guard);
+        Iter {
+            _raw: unsafe { self._raw.iter(guard) },
+        }
+    }
+
+    /// The keys `iter()` would yield
+    pub fn keys<'g>(&'g self, guard: &'g Guard) -> Keys<'g, K, V, S> {
+        Keys { _iter: self.iter(guard) }
+    }
+
+    /// The values `iter()` would yield
+    pub fn values<'g>(&'g self, guard: &'g Guard) -> Values<'g, K, V, S> {
+        Values { _iter: self.iter(guard) }
+    }
+
+    /// Removes the entries `iter()` would yield and yields them as they're removed, each one as
+    /// its removal took it out. Other threads may put keys in meanwhile, which may or may not be
+    /// drained, but an entry is only yielded by whichever removal took it out. The guard must come
+    /// from this map.
+    pub fn drain<'g>(&'g self, guard: &'g Guard) -> Drain<'g, K, V, S> {
+        self._raw.check_guard(guard);
+        Drain {
+            _map: self,
+            _raw: unsafe { self._raw.iter(guard) },
+            _guard: guard,
+        }
+    }
+
+    /// Moves the entries into a `HashMap`, without cloning them
+    pub fn into_std(self) -> HashMap<K, V> {
+        let mut entries = HashMap::with_capacity(self.len());
+        entries.extend(self);
+        entries
+    }
+
+    /// Returns a batch of entries and the cursor to scan the next batch from, which is 0 once the
+    /// scan is over. A scan starts from cursor 0, and may be carried on from any later cursor under
+    /// another guard, however long after. Every key in the map for the whole scan is returned
+    /// exactly once, even if the table is resized in between. Like `iter()`, keys put or removed
+    /// during the scan may or may not be.
+    ///
+    /// Each batch takes `count` buckets of the table, each with about as many entries as the table
+    /// has per slot, so the bigger the table the sparser the batches. A resize in progress is only
+    /// helped along by copying the slots of those buckets, however far it has yet to go.
+    pub fn scan<'g>(&'g self, cursor: u64, count: usize, guard: &'g Guard) -> (u64, Vec<(&'g K, &'g V)>) {
+        self._raw.check_guard(guard);
+        let (cursor, entries) = unsafe { self._raw.scan(cursor, count, guard) };
+        let entries = entries.into_iter().map(|(k, v)| unsafe { ((*k).key(), (*v).value()) }).collect();
+        (cursor, entries)
+    }
+}
+
+// Takes the in-progress mark of get_or_insert_with_once() out again unless forgotten, so that the
+// threads waiting on it don't wait forever if computing the value panics
+struct Abandoned<'a, K: Eq + Hash, V: Eq, S: BuildHasher> {
+    _map: &'a NonBlockingHashMap<K, V, S>,
+    _key: &'a K,
+    _guard: &'a Guard<'a>,
+}
+
+impl<'a, K: Eq + Hash, V: Eq, S: BuildHasher> Drop for Abandoned<'a, K, V, S> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self._map._raw.put_if_match(
+                self._key,
+                ptr::null_mut(),
+                tombstone(),
+                MatchingTypes::MatchValue,
+                Some(Expected::Slot(in_progress())),
+                self._guard,
+            );
+        }
+    }
+}
+
+// How many times a thread waiting on a value in progress yields before it starts to sleep
+const IN_PROGRESS_YIELDS: u32 = 8;
+// The longest it sleeps at a time, in microseconds
+const IN_PROGRESS_MAX_SLEEP_LOG: u32 = 10;
+
+// Waits a little longer the more often the value has been found still in progress: a few yields
+// for values computed quickly, then sleeps doubling from a microsecond to about a millisecond, so
+// that a long computation doesn't keep a core busy for every thread waiting on it
+fn wait_in_progress(waits: u32) {
+    if waits < IN_PROGRESS_YIELDS {
+        thread::yield_now();
+    } else {
+        let log = min(waits - IN_PROGRESS_YIELDS, IN_PROGRESS_MAX_SLEEP_LOG);
+        thread::sleep(Duration::from_micros(1 << log));
+    }
+}
+
+impl<KS: KeySlots, V: Eq, S: BuildHasher> RawMap<KS, V, S>
+where
+    KS::Key: Eq + Hash,
+{
+    fn with_capacity_and_hasher(initial_sz: usize, hash_builder: S) -> RawMap<KS, V, S> {
+        RawMap {
+            _kvs: AtomicPtr::new(box_new_mut_ptr(KVs::<KS, V>::new(table_size_for(initial_sz.min(1024 * 1024))))),
             //_reprobes: AtomicUint::new(0),
-            _last_resize: Instant::now(),
+            _created: Instant::now(),
+            _last_resize: AtomicU64::new(0),
+            _collector: Collector::new(),
+            _hasher: hash_builder,
+            _size: StripedCounter::new(),
+        }
+    }
+
+    fn guard(&self) -> Guard<'_> {
+        self._collector.pin()
+    }
+
+    // Whatever is read under a guard must stay alive for as long as the guard
+    fn check_guard(&self, guard: &Guard) {
+        assert!(guard.is_from(&self._collector), "guard is from another map");
+    }
+
+    // Never 0, which marks a slot whose hash hasn't been stored yet
+    fn hash<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
+        match self._hasher.hash_one(key) {
+            0 => 1,
+            h => h,
         }
     }
 
-    pub fn get_table_nonatomic(&self) -> *mut KVs<K, V> {
+    fn millis_since_created(&self) -> u64 {
+        self._created.elapsed().as_millis() as u64
+    }
+
+    fn get_table_nonatomic(&self) -> *mut KVs<KS, V> {
         self._kvs.load(MEMORY_ORDERING)
     }
 
@@ -123,7 +748,7 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
     // Since this routine has a fast cutout for copy-already-started, callers
     // MUST 'help_copy' lest we have a path which forever runs through
     // 'resize' only to discover a copy-in-progress which never progresses.
-    unsafe fn resize(&self, kvs: *mut KVs<K, V>) -> *mut KVs<K, V> {
+    unsafe fn resize(&self, kvs: *mut KVs<KS, V>) -> *mut KVs<KS, V> {
         let mut newkvs = (*kvs)._chm.get_newkvs_nonatomic();
         // See if resize is already in progress
         if !newkvs.is_null() {
@@ -132,7 +757,7 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         }
 
         let oldlen: usize = (*kvs).len();
-        let sz = (*kvs)._chm._size.load(MEMORY_ORDERING);
+        let sz = self.len();
         let mut newsz = sz;
 
         if sz >= oldlen >> 2 {
@@ -142,10 +767,12 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             }
         }
 
-        let tm = Instant::now();
+        // A table of the same size only helps by leaving dead keys behind. With few of them it
+        // would have the very probe sequences which got too long in this one, and so on forever.
+        let tm = self.millis_since_created();
         if newsz <= oldlen
-            && tm.duration_since(self._last_resize) <= Duration::new(1, 0)
-            && (*kvs)._chm._slots.load(MEMORY_ORDERING) >= sz << 1
+            && (tm.saturating_sub(self._last_resize.load(MEMORY_ORDERING)) <= 1000
+                || (*kvs)._chm._slots.load(MEMORY_ORDERING) < sz << 1)
         {
             newsz = oldlen << 1;
         }
@@ -170,12 +797,12 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         if num_resizer == 0 {
             // we're the first, let's allocate the new table
             //println!("we are the first thread to reallocate");
-            let newkvs_alloc = box_new_mut_ptr(KVs::<K, V>::new(1 << log2));
+            let newkvs_alloc = box_new_mut_ptr(KVs::<KS, V>::new(1 << log2));
             if (*kvs)
                 ._chm
                 ._newkvs
-                .compare_and_swap(newkvs, newkvs_alloc, MEMORY_ORDERING)
-                != newkvs
+                .compare_exchange(newkvs, newkvs_alloc, MEMORY_ORDERING, MEMORY_ORDERING)
+                .is_err()
             {
                 // impossible
                 panic!("_chm._newkvs changed by unknown thread");
@@ -195,230 +822,226 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         }
     }
 
-    pub fn put<'a>(&mut self, key: K, newval: V) -> &'a V {
-        unsafe { self.put_if_match(key, newval, MatchingTypes::MatchAll, None) }
-    }
-
-    unsafe fn put_if_match<'a>(
-        &mut self,
-        key: K,
-        newval: V,
-        matchingtype: MatchingTypes,
-        expval: Option<V>,
-    ) -> &'a V {
-        let table = self.get_table_nonatomic();
-        self.put_if_match_to_kvs(table, key, newval, matchingtype, expval)
-    }
-
-    unsafe fn put_if_match_to_kvs<'a>(
-        &mut self,
-        kvs: *mut KVs<K, V>,
-        key: K,
-        newval: V,
+    // Values replaced by the put are retired to the guard. keyword is stored if the key isn't in
+    // the map yet. It may be EMPTY if the put can only succeed with the key in the map already.
+    // putval is taken over: a boxed value is freed if it doesn't get in.
+    unsafe fn put_if_match<Q>(
+        &self,
+        key: &Q,
+        keyword: KS::Word,
+        putval: *mut ValueHolder<V>,
         matchingtype: MatchingTypes,
-        expval: Option<V>,
-    ) -> &'a V {
-        let new_expval = expval.map(|v| box_new_mut_ptr(ValueHolder::Value(v)));
-        let returnval = self.put_if_match_impl(
-            kvs,
-            box_new_mut_ptr(KeyHolder::Key(key)),
-            box_new_mut_ptr(ValueHolder::Value(newval)),
+        expval: Option<Expected<V>>,
+        guard: &Guard,
+    ) -> Result<*mut ValueHolder<V>, *mut ValueHolder<V>>
+    where
+        KS::Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let mut keysrc = None;
+        let result = self.put_if_match_impl(
+            self.get_table_nonatomic(),
+            key,
+            self.hash(key),
+            keyword,
+            putval,
             matchingtype,
-            new_expval,
+            expval.as_ref(),
+            &mut keysrc,
+            guard,
         );
-        (*returnval).value()
+        if keysrc.is_none() {
+            // The key was already in, or putval had nowhere to go
+            KS::free(keyword);
+        }
+        match result {
+            Ok(oldval) if is_boxed(oldval) => guard.defer_drop(oldval),
+            Err(curval) if curval != putval && is_boxed(putval) => drop(Box::from_raw(putval)),
+            _ => {}
+        }
+        result
     }
 
+    // Returns Ok with the replaced value if putval got in, or Err with the current value if the
+    // match failed. Empty (null) or TombStone means no value.
+    // keysrc is where the key was last put: the table it's put into next becomes its owner, and
+    // keysrc is updated to match. It's left None if the key never got into any table.
     // FIXME: clippy::cyclomatic_complexity: the function has a cyclomatic complexity of 26
-    unsafe fn put_if_match_impl(
-        &mut self,
-        kvs: *mut KVs<K, V>,
-        key: *mut KeyHolder<K>,
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn put_if_match_impl<Q>(
+        &self,
+        kvs: *mut KVs<KS, V>,
+        key: &Q,
+        fullhash: u64,
+        keyword: KS::Word,
         putval: *mut ValueHolder<V>,
         matchingtype: MatchingTypes,
-        expval: Option<*mut ValueHolder<V>>,
-    ) -> *mut ValueHolder<V> {
-        //let mut debugval = 0 as *mut Value<V>;
-        //if expval.is_some() { debugval = expval.unwrap() }
-        assert!(!putval.is_null());     // Never put a ValueEmpty type
-        assert!(!(*putval).is_prime()); // Never put a Prime type
-        assert!(matchingtype != MatchingTypes::MatchValue || !expval.is_none()); // If matchingtype==MatchValue then expval must contain something
-        if expval.is_some() {
-            assert!(!(*expval.unwrap()).is_prime());
-        } // Never expect a Prime type
-
-        let mut hasher = DefaultHasher::new();
-        (*key).hash(&mut hasher);
-        let fullhash = hasher.finish();
+        expval: Option<&Expected<V>>,
+        keysrc: &mut Option<(*mut KVs<KS, V>, usize)>,
+        guard: &Guard,
+    ) -> Result<*mut ValueHolder<V>, *mut ValueHolder<V>>
+    where
+        KS::Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        assert!(!putval.is_null()); // Never put a ValueEmpty type
+        assert!(!is_prime(putval)); // Never put a Prime type
+        assert!(matchingtype != MatchingTypes::MatchValue || expval.is_some()); // If matchingtype==MatchValue then expval must contain something
+        // A copy from an older table neither helps copying nor changes the size
+        let is_copy = matchingtype == MatchingTypes::FromCopySlot;
+
         let len = (*kvs).len();
         let mut idx: usize = fullhash as usize & (len - 1);
         let mut reprobe_cnt: usize = 0;
-        let mut k = (*kvs).get_key_nonatomic_at(idx);
-        let mut v = (*kvs).get_value_nonatomic_at(idx);
-        // Determine if expval is empty
-        let mut expval_not_empty = false;
-        if matchingtype == MatchingTypes::MatchValue {
-            if !expval.unwrap().is_null() {
-                expval_not_empty = true;
-            }
-        } else {
-            expval_not_empty = true;
-        }
+        let mut k;
+        let mut v;
         // Probing/Re-probing
         loop {
-            if k.is_null() {
+            v = (*kvs).get_value_nonatomic_at(idx);
+            k = (*kvs).get_key_nonatomic_at(idx);
+            if k == KS::EMPTY {
                 // Found an available key slot
-                if (*putval).is_tombstone() {
-                    return putval;
-                } // Never change KeyEmpty to KeyTombStone
-                if (*kvs)._ks.cas(idx, k, key) == k {
+                if keyword == KS::EMPTY || is_tombstone(putval) {
+                    return Err(ptr::null_mut());
+                } // Never change KeyEmpty to KeyTombStone: a removed key needs no slot
+                if (*kvs)._ks.cas(idx, k, keyword) == k {
                     // Add key to the slot
                     (*kvs)._chm._slots.fetch_add(1, MEMORY_ORDERING); // Add 1 to the number of used slots
-                    (*kvs)._hashes[idx] = fullhash;
+                    (&(*kvs)._hashes)[idx].store(fullhash, MEMORY_ORDERING);
+                    if let Some((srckvs, srcidx)) = keysrc.replace((kvs, idx)) {
+                        (*srckvs)._ks.set_moved(srcidx);
+                    }
                     break;
                 }
                 k = (*kvs).get_key_nonatomic_at(idx);
-                v = (*kvs).get_value_nonatomic_at(idx);
-                assert!(!k.is_null());
+                assert!(k != KS::EMPTY);
             }
-            //fence(MEMORY_ORDERING);
-            if k == key || (*k) == (*key) {
+            if k == keyword {
+                // Another copy of the slot got the key here first. It's here the key moves on from,
+                // so this is the slot to hand it over from if it's put into a newer table.
+                *keysrc = Some((kvs, idx));
+                break;
+            }
+            if (*kvs).hash_may_match(idx, fullhash) && KS::matches(k, key) {
                 break;
             }
             // Start re-probing
             reprobe_cnt += 1;
-            if reprobe_cnt >= REPROBE_LIMIT || (*key).is_tombstone() {
-                // Enter state {KeyTombStone, Empty}; steal exucution path for optimization; let helper save the day.
+            if reprobe_cnt >= REPROBE_LIMIT || KS::is_tombstone(k) {
+                // Either the table is too crowded or the old table is being copied ({KeyTombStone, Empty}
+                // is only made by copy_slot), so put into the new table instead.
                 let newkvs = self.resize(kvs);
-                if expval_not_empty {
-                    self.help_copy();
+                if !is_copy {
+                    self.help_copy(guard);
                 }
-                return self.put_if_match_impl(newkvs, key, putval, matchingtype, expval); // Put in the new table instead
+                return self.put_if_match_impl(newkvs, key, fullhash, keyword, putval, matchingtype, expval, keysrc, guard);
             }
             idx = (idx + 1) & (len - 1);
-            k = (*kvs).get_key_nonatomic_at(idx);
-            v = (*kvs).get_value_nonatomic_at(idx);
         }
         // End probe/re-probing
 
-        if (*putval) == (*v) {
-            return v;
+        if putval == v {
+            return Err(v);
         } // Steal path exucution for optimization; let helper save the day.
-        if !(*kvs)._chm.get_newkvs_nonatomic().is_null()
-            && (( (*v).is_tombstone() && (*kvs).table_full(reprobe_cnt) ) || // Resize if the table is full.
-                (*v).is_prime())
-        // I don't understand this, but I take it from the original code anyway. It is some sort of invalid state caused by compilier's optimization.
+
+        let mut newkvs = (*kvs)._chm.get_newkvs_nonatomic();
+        if newkvs.is_null()
+            && ((v.is_null() && (*kvs).table_full(reprobe_cnt)) // Resize if the table is full.
+                || is_prime(v))
+        // A Prime can only be seen once a new table has been installed; it just hasn't been read yet.
         {
-            self.resize(kvs);
+            newkvs = self.resize(kvs);
         }
-        if !(*kvs)._chm.get_newkvs_nonatomic().is_null() {
-            // Check for the last time if kvs is the newest table
-            let expval_is_empty = {
-                match expval {
-                    Some(val) => val.is_null(),
-                    None => true,
-                }
-            };
-            let copied_kvs = self.copy_slot_and_check(kvs, idx, !expval_is_empty); // If expval is empty then don't help (expval is empty only if this function is called from copy_slot)
-            return self.put_if_match_impl(copied_kvs, key, putval, matchingtype, expval);
+        if !newkvs.is_null() {
+            // This is not the newest table: copy the slot over, then retry in the new table
+            let copied_kvs = self.copy_slot_and_check(kvs, idx, !is_copy, guard);
+            return self.put_if_match_impl(copied_kvs, key, fullhash, keyword, putval, matchingtype, expval, keysrc, guard);
         }
 
         // This table is the newest, so we can start entering the state machine.
         loop {
-            assert!(!(*v).is_prime()); // If there is a Prime than this cannot be the newest table.
-            if matchingtype!=MatchingTypes::MatchAll && // If expval is not a wildcard
-                !( matchingtype==MatchingTypes::MatchAllNotEmpty && !v.is_null() && !(*v).is_tombstone() )
-            // If expval is not a TombStone or Empty
-            {
-                assert!(!expval.is_none());
-                assert!(matchingtype == MatchingTypes::MatchValue);
-                if v!=expval.unwrap() && // if v!= expval (pointer)
-                    !(v.is_null() && (*expval.unwrap()).is_tombstone()) && // If we expect a TombStone and v is empty, it should be a match.
-                    *expval.unwrap()!=*v
-                // expval==Empty or *expval==*v
-                {
-                    return v; // do nothing, just return the old value.
-                }
+            assert!(!is_prime(v)); // If there is a Prime than this cannot be the newest table.
+            if !is_match(&matchingtype, expval, v) {
+                return Err(v); // do nothing, just return the old value.
             }
 
             // Finally, add some values.
             if (*kvs)._vs.cas(idx, v, putval) == v {
-                if expval_not_empty {
-                    if (v.is_null() || (*v).is_tombstone()) && !(*putval).is_tombstone() {
-                        (*kvs)._chm._size.fetch_add(1, MEMORY_ORDERING);
+                if !is_copy {
+                    let was_empty = is_absent(v);
+                    if was_empty && !is_absent(putval) {
+                        self._size.add(1);
                     }
-                    if !(v.is_null() || (*v).is_tombstone()) && (*putval).is_tombstone() {
-                        (*kvs)._chm._size.fetch_sub(1, MEMORY_ORDERING);
+                    if !was_empty && is_absent(putval) {
+                        self._size.add(-1);
                     }
                 }
-                if v.is_null() && expval_not_empty {
-                    return box_new_mut_ptr(ValueHolder::Tombstone);
-                } else {
-                    return v;
-                }
+                return Ok(v);
             }
             v = (*kvs).get_value_nonatomic_at(idx);
-            if (*v).is_prime() {
-                let copied_kvs = self.copy_slot_and_check(kvs, idx, expval_not_empty);
-                return self.put_if_match_impl(copied_kvs, key, putval, matchingtype, expval);
+            if is_prime(v) {
+                let copied_kvs = self.copy_slot_and_check(kvs, idx, !is_copy, guard);
+                return self.put_if_match_impl(copied_kvs, key, fullhash, keyword, putval, matchingtype, expval, keysrc, guard);
+            }
+            // Whoever beat us to it put the very same sentinel in. InProgress in particular also
+            // matches an expected absent value, but only one thread may take it from absent.
+            if putval == v {
+                return Err(v);
             }
         }
     }
 
-    pub fn get(&mut self, key: K) -> Option<&V> {
-        let table = self.get_table_nonatomic();
-        let maybe_val =
-            // FIXME: the new boxed key will be leaked after into_raw()!
-            // plus, there's no need to wrap key in Key<K> in get() at all.
-            unsafe { self.get_impl(table, box_new_mut_ptr(KeyHolder::Key(key))) };
-        maybe_val.map(|v| unsafe { (*v).value() })
-    }
-
     // Compute hash only once
-    unsafe fn get_impl(&mut self, kvs: *mut KVs<K, V>, key: *mut KeyHolder<K>) -> Option<*mut ValueHolder<V>> {
-        let mut hasher = DefaultHasher::new();
-        (*key).hash(&mut hasher);
-        let fullhash = hasher.finish();
-        self.get_impl_supply_hash(kvs, key, fullhash)
+    unsafe fn get_impl<Q>(&self, kvs: *mut KVs<KS, V>, key: &Q, guard: &Guard) -> Option<*mut ValueHolder<V>>
+    where
+        KS::Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let fullhash = self.hash(key);
+        self.get_impl_supply_hash(kvs, key, fullhash, guard)
     }
 
-    unsafe fn get_impl_supply_hash(
-        &mut self,
-        kvs: *mut KVs<K, V>,
-        key: *mut KeyHolder<K>,
+    unsafe fn get_impl_supply_hash<Q>(
+        &self,
+        kvs: *mut KVs<KS, V>,
+        key: &Q,
         fullhash: u64,
-    ) -> Option<*mut ValueHolder<V>> {
+        guard: &Guard,
+    ) -> Option<*mut ValueHolder<V>>
+    where
+        KS::Key: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
         let len = (*kvs).len();
         let mut idx = (fullhash & (len - 1) as u64) as usize;
         let mut reprobe_cnt: usize = 0;
         loop {
             let k = (*kvs).get_key_nonatomic_at(idx);
             let v = (*kvs).get_value_nonatomic_at(idx);
-            if k.is_null() {
+            if k == KS::EMPTY {
                 return None;
             }
-            //fence(MEMORY_ORDERING);
-            if (*k) == (*key) {
-                if !(*v).is_prime() {
-                    if (*v).is_tombstone() {
-                        return None;
-                    } else {
-                        return Some(v);
-                    }
+            // Read the new table before comparing the key: if the key has moved on it must be there
+            let newkvs = (*kvs)._chm.get_newkvs_nonatomic();
+            if (*kvs).hash_may_match(idx, fullhash) && KS::matches(k, key) {
+                if v.is_null() {
+                    // The key is in but its value has not been put yet
+                    return None;
+                }
+                if is_prime(v) {
+                    let table = self.copy_slot_and_check(kvs, idx, true, guard);
+                    return self.get_impl_supply_hash(table, key, fullhash, guard);
+                } else if is_absent(v) {
+                    return None;
                 } else {
-                    let table = self.copy_slot_and_check(kvs, idx, true);
-                    return self.get_impl_supply_hash(table, key, fullhash);
+                    return Some(v);
                 }
             }
             reprobe_cnt += 1;
-            if reprobe_cnt >= REPROBE_LIMIT || (*k).is_tombstone() {
-                if !(*kvs)._chm.get_newkvs_nonatomic().is_null() {
-                    self.help_copy();
-                    return self.get_impl_supply_hash(
-                        (*kvs)._chm.get_newkvs_nonatomic(),
-                        key,
-                        fullhash,
-                    );
+            if reprobe_cnt >= REPROBE_LIMIT || KS::is_tombstone(k) {
+                if !newkvs.is_null() {
+                    self.help_copy(guard);
+                    return self.get_impl_supply_hash(newkvs, key, fullhash, guard);
                 } else {
                     return None;
                 }
@@ -428,164 +1051,153 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
     }
 
     unsafe fn copy_slot_and_check(
-        &mut self,
-        oldkvs: *mut KVs<K, V>,
+        &self,
+        oldkvs: *mut KVs<KS, V>,
         idx: usize,
         should_help: bool,
-    ) -> *mut KVs<K, V> {
-        //fence(MEMORY_ORDERING);
+        guard: &Guard,
+    ) -> *mut KVs<KS, V> {
         assert!(!(*oldkvs)._chm.get_newkvs_nonatomic().is_null());
-        if self.copy_slot(oldkvs, idx) {
-            self.copy_check_and_promote(oldkvs, 1);
+        if self.copy_slot(oldkvs, idx, guard) {
+            self.copy_check_and_promote(oldkvs, 1, guard);
         }
 
         if should_help {
-            self.help_copy();
+            self.help_copy(guard);
         }
         (*oldkvs)._chm.get_newkvs_nonatomic()
     }
 
-    unsafe fn copy_check_and_promote(&mut self, oldkvs: *mut KVs<K, V>, work_done: usize) {
+    unsafe fn copy_check_and_promote(&self, oldkvs: *mut KVs<KS, V>, work_done: usize, guard: &Guard) {
         let oldlen = (*oldkvs).len();
         let mut copy_done = (*oldkvs)._chm._copy_done.load(MEMORY_ORDERING);
         assert!(copy_done + work_done <= oldlen);
         if work_done > 0 {
-            while (*oldkvs)._chm._copy_done.compare_and_swap(
+            while let Err(current) = (*oldkvs)._chm._copy_done.compare_exchange(
                 copy_done,
                 copy_done + work_done,
                 MEMORY_ORDERING,
-            ) != copy_done
-            {
-                copy_done = (*oldkvs)._chm._copy_done.load(MEMORY_ORDERING);
+                MEMORY_ORDERING,
+            ) {
+                copy_done = current;
             }
             assert!(copy_done + work_done <= oldlen);
         }
 
         if copy_done + work_done == oldlen
             && self._kvs.load(MEMORY_ORDERING) == oldkvs
-            && (self._kvs.compare_and_swap(
-                oldkvs,
-                (*oldkvs)._chm.get_newkvs_nonatomic(),
-                MEMORY_ORDERING,
-            ) == oldkvs)
+            && self
+                ._kvs
+                .compare_exchange(
+                    oldkvs,
+                    (*oldkvs)._chm.get_newkvs_nonatomic(),
+                    MEMORY_ORDERING,
+                    MEMORY_ORDERING,
+                )
+                .is_ok()
         {
             //println!("---obsolete---")
             //print_kvs(oldkvs);
-            // FIXME: drop(Box::from_raw(oldkvs));
-            self._last_resize = Instant::now();
+            guard.defer(oldkvs as *mut u8, free_kvs::<KS, V>);
+            self._last_resize.store(self.millis_since_created(), MEMORY_ORDERING);
         }
     }
 
-    unsafe fn copy_slot(&mut self, oldkvs: *mut KVs<K, V>, idx: usize) -> bool {
-        let mut key = (*oldkvs).get_key_nonatomic_at(idx);
-
+    // Returns true if this call finished copying the slot, so that each slot is only counted once
+    unsafe fn copy_slot(&self, oldkvs: *mut KVs<KS, V>, idx: usize, guard: &Guard) -> bool {
         // State transition: {Empty, Empty} -> {KeyTombStone, Empty}
+        // Blindly tombstone an empty key slot, so no fresh put can land in the old table any more.
         // ---------------------------------------------------------
-        let tombstone_ptr = box_new_mut_ptr(KeyHolder::Tombstone);
-        while key.is_null() {
-            if (*oldkvs)._ks.cas(idx, key, tombstone_ptr) == key {
-                // Attempt {Empty, Empty} -> {KeyTombStone, Empty}
-                // FIXME: memory leak
-                //drop(Box::from_raw(key));
-                return true;
-            }
-            key = (*oldkvs).get_key_nonatomic_at(idx);
-        }
-        // ---------------------------------------------------------
-
-        // Enter state: {KeyTombStone, Empty}
-        // ---------------------------------------------------------
-        if (*key).is_tombstone() {
-            if key != tombstone_ptr {
-                //drop(Box::from_raw(tombstone_ptr));
+        let mut key = (*oldkvs).get_key_nonatomic_at(idx);
+        if key == KS::EMPTY {
+            key = (*oldkvs)._ks.cas(idx, key, KS::tombstone());
+            if key == KS::EMPTY {
+                key = KS::tombstone();
             }
-            return false;
         }
         // ---------------------------------------------------------
 
         // State transition: {Key, Empty} -> {Key, ValueTombPrime} or {Key, ValueTombStone} -> {Key, ValueTombPrime} or {Key, Value}->{Key, Value.get_prime()}
+        // Tag whatever is in the old table, so it cannot be updated any more. The value stays where it is.
+        // InProgress is copied like a value, so whoever waits on it in the old table still does in the new one.
         // -------------------------------------------------------------------------------------------------------
-        let tombstone_ptr = box_new_mut_ptr(ValueHolder::Prime(Box::new(ValueHolder::Tombstone)));
+        let tombprime = tombprime();
         let mut oldvalue = (*oldkvs).get_value_nonatomic_at(idx);
-        while !(*oldvalue).is_prime() {
-            let primed = {
-                if oldvalue.is_null() {
-                    tombstone_ptr
-                } else {
-                    box_new_mut_ptr(ValueHolder::to_prime(Box::from_raw(oldvalue)))
-                }
+        while !is_prime(oldvalue) {
+            let primed = if oldvalue.is_null() || is_tombstone(oldvalue) {
+                tombprime
+            } else {
+                prime(oldvalue)
             };
-            // oldvalue is now owned by primed so no leak with cas here.
             if (*oldkvs)._vs.cas(idx, oldvalue, primed) == oldvalue {
-                if (*primed).is_tombstone() {
+                if primed == tombprime {
+                    // Transition: {Key, Empty} -> {Key, ValueTombPrime} or {Key, ValueTombStone} -> {Key, ValueTombPrime}
+                    // Nothing to copy, and the slot is done.
                     return true;
                 }
-                // Transition: {Key, Empty} -> {Key, ValueTombPrime} or {Key, ValueTombStone} -> {Key, ValueTombPrime}
-                else {
-                    // Transition: {Key, Value} -> {Key, Value'}
-                    // FIXME: oldvalue leaked
-                    oldvalue = primed;
-                    break;
-                }
+                // Transition: {Key, Value} -> {Key, Value'}
+                oldvalue = primed;
+                break;
             }
             oldvalue = (*oldkvs).get_value_nonatomic_at(idx);
         }
         // -------------------------------------------------------------------------------------------------------
 
-        let tombprime = ValueHolder::Prime(Box::new(ValueHolder::Tombstone));
-
         // Enter state: {Key, ValueTombPrime}
         // ---------------------------------------------------------
-        if (*oldvalue) == tombprime {
+        if oldvalue == tombprime {
             return false;
         }
         // ---------------------------------------------------------
 
-        // State transition: {Key, Value.get_prime()} -> {KeyTombStone, ValueTombPrime}
+        // State transition: {Key, Value.get_prime()} -> {Key, ValueTombPrime}
+        // Move the value box itself over. Only the thread which fills the empty slot in the new table
+        // hands it over; any others find it already there.
         // ---------------------------------------------------------
-        let old_unprimed = Box::into_raw(ValueHolder::unwrap_prime(*Box::from_raw(oldvalue)));
-        // oldvalue leaked
-        assert!((*old_unprimed) != tombprime);
+        let old_unprimed = unprime(oldvalue);
+        assert!(!is_tombstone(old_unprimed));
         let newkvs = (*oldkvs)._chm.get_newkvs_nonatomic();
-        let emptyval: *mut ValueHolder<V> = std::ptr::null_mut();
-
-        self.put_if_match_impl(
-            newkvs,
-            key,
-            old_unprimed,
-            MatchingTypes::MatchValue,
-            Some(emptyval),
-        );
-
-        let tombprime_ptr = box_new_mut_ptr(tombprime);
-
-        // Enter state: {Key, Value.get_prime()} (intermediate)
-        oldvalue = (*oldkvs).get_value_nonatomic_at(idx); // Check again, just in case...
-        while (*oldvalue) != (*tombprime_ptr) {
-            if (*oldkvs)._vs.cas(idx, oldvalue, tombprime_ptr) == oldvalue
-            {
-                // FIXME: oldvalue leaked
-                return true;
+        // Rehash only if the key's own put hasn't stored the hash yet
+        let fullhash = match (&(*oldkvs)._hashes)[idx].load(MEMORY_ORDERING) {
+            0 => self.hash(KS::key(&key)),
+            h => h,
+        };
+        let copied_into_new = self
+            .put_if_match_impl(
+                newkvs,
+                KS::key(&key),
+                fullhash,
+                key,
+                old_unprimed,
+                MatchingTypes::FromCopySlot,
+                None,
+                &mut Some((oldkvs, idx)),
+                guard,
+            )
+            .is_ok();
+
+        // Now that the value is visible in the new table, hide the old one forever. The new table
+        // owns the value box now, so there's nothing to retire.
+        while oldvalue != tombprime {
+            if (*oldkvs)._vs.cas(idx, oldvalue, tombprime) == oldvalue {
+                break;
             }
             oldvalue = (*oldkvs).get_value_nonatomic_at(idx);
         }
         // ---------------------------------------------------------
 
-        false // State jump to {KeyTombStone, ValueTombPrime} for threads that lost the competition
+        copied_into_new
     }
 
-    unsafe fn help_copy(&mut self) {
-        if !(*self.get_table_nonatomic())
-            ._chm
-            .get_newkvs_nonatomic()
-            .is_null()
-        {
-            let kvs: *mut KVs<K, V> = self.get_table_nonatomic();
-            self.help_copy_impl(kvs, false);
+    unsafe fn help_copy(&self, guard: &Guard) {
+        // Read the table once: if it's promoted in between, the newer one may have no copy to help
+        let kvs: *mut KVs<KS, V> = self.get_table_nonatomic();
+        if !(*kvs)._chm.get_newkvs_nonatomic().is_null() {
+            self.help_copy_impl(kvs, false, guard);
         }
     }
 
-    unsafe fn help_copy_impl(&mut self, oldkvs: *mut KVs<K, V>, copy_all: bool) {
+    unsafe fn help_copy_impl(&self, oldkvs: *mut KVs<KS, V>, copy_all: bool, guard: &Guard) {
         //fence(MEMORY_ORDERING);
         assert!(!(*oldkvs)._chm.get_newkvs_nonatomic().is_null());
         let oldlen = (*oldkvs).len();
@@ -597,11 +1209,16 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             if !panic_start {
                 copy_idx = (*oldkvs)._chm._copy_idx.load(MEMORY_ORDERING);
                 while copy_idx < oldlen << 1
-                    && (*oldkvs)._chm._copy_idx.compare_and_swap(
-                        copy_idx,
-                        copy_idx + min_copy_work,
-                        MEMORY_ORDERING,
-                    ) != copy_idx
+                    && (*oldkvs)
+                        ._chm
+                        ._copy_idx
+                        .compare_exchange(
+                            copy_idx,
+                            copy_idx + min_copy_work,
+                            MEMORY_ORDERING,
+                            MEMORY_ORDERING,
+                        )
+                        .is_err()
                 {
                     copy_idx = (*oldkvs)._chm._copy_idx.load(MEMORY_ORDERING);
                 }
@@ -616,12 +1233,12 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             //}
             let mut work_done = 0;
             for i in 0..min_copy_work {
-                if self.copy_slot(oldkvs, (copy_idx + i) & (oldlen - 1)) {
+                if self.copy_slot(oldkvs, (copy_idx + i) & (oldlen - 1), guard) {
                     work_done += 1;
                 }
             }
             if work_done > 0 {
-                self.copy_check_and_promote(oldkvs, work_done);
+                self.copy_check_and_promote(oldkvs, work_done, guard);
             }
 
             copy_idx += min_copy_work;
@@ -630,14 +1247,14 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
                 return;
             }
         }
-        self.copy_check_and_promote(oldkvs, 0);
+        self.copy_check_and_promote(oldkvs, 0, guard);
     }
 
-    pub fn get_kvs_level(&self, level: u32) -> Option<*mut KVs<K, V>> {
-        NonBlockingHashMap::get_kvs_level_impl(self.get_table_nonatomic(), level)
+    fn get_kvs_level(&self, level: u32) -> Option<*mut KVs<KS, V>> {
+        Self::get_kvs_level_impl(self.get_table_nonatomic(), level)
     }
 
-    fn get_kvs_level_impl(kvs: *mut KVs<K, V>, level: u32) -> Option<*mut KVs<K, V>> {
+    fn get_kvs_level_impl(kvs: *mut KVs<KS, V>, level: u32) -> Option<*mut KVs<KS, V>> {
         if kvs.is_null() {
             return None;
         }
@@ -645,7 +1262,7 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
             Some(kvs)
         } else {
             unsafe {
-                NonBlockingHashMap::get_kvs_level_impl(
+                Self::get_kvs_level_impl(
                     (*kvs)._chm.get_newkvs_nonatomic(),
                     level - 1,
                 )
@@ -653,55 +1270,426 @@ impl<K: Eq + Hash, V: Eq> NonBlockingHashMap<K, V> {
         }
     }
 
-    pub fn capacity(&self) -> usize {
+    fn capacity(&self) -> usize {
+        let _guard = self._collector.pin();
         unsafe { (*self._kvs.load(MEMORY_ORDERING)).len() }
     }
-}
-
-// debuging functions
-unsafe fn print_table<K: Eq + Hash + ToString, V: Eq + ToString>(table: &NonBlockingHashMap<K, V>) {
-    print_kvs(table.get_table_nonatomic());
-}
 
-pub fn print_all<K: Eq + Hash + ToString, V: Eq + ToString>(table: &NonBlockingHashMap<K, V>) {
-    let mut kvs = table.get_table_nonatomic();
-    let mut i = 0;
-    while !kvs.is_null() {
-        println!("---Table {}---", i);
-        unsafe { print_kvs(kvs) };
-        i += 1;
-        kvs = unsafe { (*kvs)._chm.get_newkvs_nonatomic() };
+    // Puts and removes under way may or may not be counted yet
+    fn len(&self) -> usize {
+        self._size.sum().max(0) as usize
     }
-}
 
-unsafe fn print_kvs<K: Eq + Hash + ToString, V: Eq + ToString>(kvs: *mut KVs<K, V>) {
-    for i in 0..(*kvs).len() {
-        print!(
-            "{}: ({}, ",
-            i,
-            key_to_string((*kvs).get_key_nonatomic_at(i))
-        );
-        print!("{}, ", value_to_string((*kvs).get_value_nonatomic_at(i)));
-        println!("{})", (*kvs)._hashes[i]);
+    fn estimated_len(&self) -> usize {
+        self._size.estimate(self.millis_since_created()).max(0) as usize
     }
-}
 
-unsafe fn key_to_string<K: Eq + Hash + ToString>(key: *mut KeyHolder<K>) -> String {
-    match key.as_ref() {
-        None => String::from("EMPTY"),
-        Some(KeyHolder::Key(k)) => k.to_string(),
-        Some(KeyHolder::Tombstone) => String::from("TOMBSTONE"),
+    // The newest table, once any copy in progress has been finished, so every key in the map by
+    // now is in it
+    unsafe fn copied_table(&self, guard: &Guard) -> *mut KVs<KS, V> {
+        let mut kvs = self.get_table_nonatomic();
+        while !(*kvs)._chm.get_newkvs_nonatomic().is_null() {
+            self.help_copy_impl(kvs, true, guard);
+            kvs = self.get_table_nonatomic();
+        }
+        kvs
     }
-}
 
-unsafe fn value_to_string<V: Eq + ToString>(value: *mut ValueHolder<V>) -> String {
-    match value.as_ref() {
-        None => String::from("EMPTY"),
-        Some(ValueHolder::Tombstone) => String::from("TOMBSTONE"),
-        Some(ValueHolder::Value(v)) => v.to_string(),
-        Some(ValueHolder::Prime(box ValueHolder::Tombstone)) => String::from("TOMBPRIME"),
-        Some(ValueHolder::Prime(box ValueHolder::Value(v))) => format!("Prime({})", v.to_string()),
-        _ => panic!("bad nested prime value"),
+    // Finishes any copy left under way, so the newest table is the only one with keys in it
+    fn finish_copy(&mut self) {
+        let guard = self.guard();
+        unsafe { self.copied_table(&guard) };
+    }
+
+    // The key in a slot and its hash, if it has a key
+    unsafe fn slot_key(&self, kvs: *mut KVs<KS, V>, idx: usize) -> Option<(KS::Word, u64)> {
+        let k = (*kvs).get_key_nonatomic_at(idx);
+        if k == KS::EMPTY || KS::is_tombstone(k) {
+            return None;
+        }
+        // The thread which put the key stores its hash right after, but another thread copying
+        // the slot may have found the key and put the value in before that
+        let fullhash = match (&(*kvs)._hashes)[idx].load(MEMORY_ORDERING) {
+            0 => self.hash(KS::key(&k)),
+            h => h,
+        };
+        Some((k, fullhash))
+    }
+
+    // The entry in a slot with the given key, if it has a value. The value is looked up in
+    // whichever newer table it has been copied to.
+    unsafe fn slot_entry(
+        &self,
+        kvs: *mut KVs<KS, V>,
+        idx: usize,
+        k: KS::Word,
+        fullhash: u64,
+        guard: &Guard,
+    ) -> Option<RawEntry<KS, V>> {
+        let mut v = (*kvs).get_value_nonatomic_at(idx);
+        if is_prime(v) {
+            let newkvs = self.copy_slot_and_check(kvs, idx, true, guard);
+            v = self.get_impl_supply_hash(newkvs, KS::key(&k), fullhash, guard)?;
+        }
+        if is_absent(v) {
+            None
+        } else {
+            Some((k, v))
+        }
+    }
+
+    unsafe fn iter<'g>(&'g self, guard: &'g Guard) -> RawIter<'g, KS, V, S> {
+        let len = (*self.get_table_nonatomic()).len();
+        RawIter {
+            _map: self,
+            _shift: u64::BITS - len.trailing_zeros(),
+            _next: Some(0),
+            _kvs: ptr::null_mut(),
+            _from: 0,
+            _slots: ScanSlots::default(),
+            _guard: guard,
+        }
+    }
+
+    // Visits count home slots of the current table from the cursor on and returns the cursor to
+    // carry on from, which is 0 once the last one has been visited. Home slots are taken in the
+    // order of their bits reversed, so the cursor of a key is its hash with the bits reversed
+    // whatever the size of the table: a scan returns the keys with a cursor from the given one up
+    // to the next, and however the table grows in between no key is skipped or returned twice.
+    unsafe fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        guard: &Guard,
+    ) -> (u64, Vec<RawEntry<KS, V>>) {
+        let kvs = self.get_table_nonatomic();
+        let len = (*kvs).len();
+        let shift = u64::BITS - len.trailing_zeros();
+        let last = (len - 1) as u64;
+        let bucket = (cursor >> shift).saturating_add(count.max(1) as u64 - 1).min(last);
+        let next = if bucket == last { 0 } else { (bucket + 1) << shift };
+        (next, self.scan_range(kvs, cursor, next, guard))
+    }
+
+    // The entries with a cursor from `from` up to `to`, or to the end if that's 0. A table being
+    // copied has only the slots of those keys copied, rather than the whole copy being finished,
+    // and then they're all in the newest table.
+    unsafe fn scan_range(
+        &self,
+        kvs: *mut KVs<KS, V>,
+        from: u64,
+        to: u64,
+        guard: &Guard,
+    ) -> Vec<RawEntry<KS, V>> {
+        let kvs = self.copy_range(kvs, from, to, guard);
+        scan_slots((*kvs).len(), from, to)
+            .filter_map(|(home, idx)| self.range_entry(kvs, home, idx, from, guard))
+            .collect()
+    }
+
+    // Copies the slots of the keys with a cursor from `from` up to `to` (or to the end, if 0) out
+    // of every table being copied, and returns the newest table, which has all of them
+    unsafe fn copy_range(
+        &self,
+        mut kvs: *mut KVs<KS, V>,
+        from: u64,
+        to: u64,
+        guard: &Guard,
+    ) -> *mut KVs<KS, V> {
+        loop {
+            let newkvs = (*kvs)._chm.get_newkvs_nonatomic();
+            if newkvs.is_null() {
+                return kvs;
+            }
+            for (_, idx) in scan_slots((*kvs).len(), from, to) {
+                if self.copy_slot(kvs, idx, guard) {
+                    self.copy_check_and_promote(kvs, 1, guard);
+                }
+            }
+            kvs = newkvs;
+        }
+    }
+
+    // The entry in a slot visited for the given home slot, if it's there and its key has a cursor
+    // from `from` on
+    unsafe fn range_entry(
+        &self,
+        kvs: *mut KVs<KS, V>,
+        home: usize,
+        idx: usize,
+        from: u64,
+        guard: &Guard,
+    ) -> Option<RawEntry<KS, V>> {
+        let (k, fullhash) = self.slot_key(kvs, idx)?;
+        if fullhash as usize & ((*kvs).len() - 1) != home || fullhash.reverse_bits() < from {
+            return None;
+        }
+        // Another copy may have started since, which slot_entry() follows
+        self.slot_entry(kvs, idx, k, fullhash, guard)
+    }
+}
+
+// The slots of a table of len slots which keys with a cursor from `from` up to `to` (or to the
+// end, if 0) may be in, each with the home slot it's visited for
+fn scan_slots(len: usize, from: u64, to: u64) -> ScanSlots {
+    let shift = u64::BITS - len.trailing_zeros();
+    ScanSlots {
+        _len: len,
+        _shift: shift,
+        _bucket: from >> shift,
+        _last: if to == 0 { (len - 1) as u64 } else { (to - 1) >> shift },
+        _probe: 0,
+    }
+}
+
+// The default visits no slots at all
+#[derive(Default)]
+struct ScanSlots {
+    _len: usize,
+    _shift: u32,
+    _bucket: u64,
+    _last: u64,
+    _probe: usize,
+}
+
+impl Iterator for ScanSlots {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        // A key is never put further than REPROBE_LIMIT slots from its home, and in a table with
+        // fewer slots than that the probe comes back round to the home slot
+        if self._probe == REPROBE_LIMIT.min(self._len) {
+            self._bucket += 1;
+            self._probe = 0;
+        }
+        if self._bucket > self._last {
+            return None;
+        }
+        let home = (self._bucket.reverse_bits() >> self._shift) as usize;
+        let idx = (home + self._probe) & (self._len - 1);
+        self._probe += 1;
+        Some((home, idx))
+    }
+}
+
+// ---Iteration -------------------------------------------------------------------------------------
+// The key word and a value which is neither empty, a tombstone nor a Prime
+type RawEntry<KS, V> = (<KS as KeySlots>::Word, *mut ValueHolder<V>);
+
+// Walks the buckets of the table it started on one at a time, like scan(): the slots of a bucket
+// are copied out of any table being copied, and then walked in the newest table, looking each
+// value up in whichever newer table it has been copied to since. A key has a single cursor and a
+// single slot in a table, so it's never yielded twice. Like the Java NBHM's iterators it's weakly
+// consistent: keys put or removed during the walk may or may not be seen.
+struct RawIter<'g, KS, V, S> {
+    _map: &'g RawMap<KS, V, S>,
+    // Of the table the walk started on, whose buckets it takes in turn
+    _shift: u32,
+    // The cursor of the next bucket, or None once the last one has been taken
+    _next: Option<u64>,
+    // The bucket being walked, and the cursor it starts at
+    _kvs: *mut KVs<KS, V>,
+    _from: u64,
+    _slots: ScanSlots,
+    _guard: &'g Guard<'g>,
+}
+
+impl<'g, KS: KeySlots, V: Eq, S: BuildHasher> Iterator for RawIter<'g, KS, V, S>
+where
+    KS::Key: Eq + Hash,
+{
+    type Item = RawEntry<KS, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for (home, idx) in &mut self._slots {
+                let entry = unsafe { self._map.range_entry(self._kvs, home, idx, self._from, self._guard) };
+                if entry.is_some() {
+                    return entry;
+                }
+            }
+            let from = self._next?;
+            let to = ((from >> self._shift) + 1) << self._shift;
+            self._next = if to == 0 { None } else { Some(to) };
+            unsafe {
+                let kvs = self._map.get_table_nonatomic();
+                self._kvs = self._map.copy_range(kvs, from, to, self._guard);
+                self._slots = scan_slots((*self._kvs).len(), from, to);
+            }
+            self._from = from;
+        }
+    }
+}
+
+/// The entries of a map, borrowed for as long as the guard is held
+pub struct Iter<'g, K, V, S> {
+    _raw: RawIter<'g, BoxedKeys<K>, V, S>,
+}
+
+impl<'g, K: Eq + Hash, V: Eq, S: BuildHasher> Iterator for Iter<'g, K, V, S> {
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<(&'g K, &'g V)> {
+        self._raw.next().map(|(k, v)| unsafe { ((*k).key(), (*v).value()) })
+    }
+}
+
+pub struct Keys<'g, K, V, S> {
+    _iter: Iter<'g, K, V, S>,
+}
+
+impl<'g, K: Eq + Hash, V: Eq, S: BuildHasher> Iterator for Keys<'g, K, V, S> {
+    type Item = &'g K;
+
+    fn next(&mut self) -> Option<&'g K> {
+        self._iter.next().map(|(k, _)| k)
+    }
+}
+
+pub struct Values<'g, K, V, S> {
+    _iter: Iter<'g, K, V, S>,
+}
+
+impl<'g, K: Eq + Hash, V: Eq, S: BuildHasher> Iterator for Values<'g, K, V, S> {
+    type Item = &'g V;
+
+    fn next(&mut self) -> Option<&'g V> {
+        self._iter.next().map(|(_, v)| v)
+    }
+}
+
+/// The entries moved out of a map, without cloning. Any copy the map was left in the middle of is
+/// finished first, so every entry is in the newest table.
+pub struct IntoIter<K, V, S> {
+    _map: NonBlockingHashMap<K, V, S>,
+    _idx: usize,
+}
+
+impl<K: Eq + Hash, V: Eq, S: BuildHasher> IntoIterator for NonBlockingHashMap<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, S>;
+
+    fn into_iter(mut self) -> IntoIter<K, V, S> {
+        self._raw.finish_copy();
+        IntoIter { _map: self, _idx: 0 }
+    }
+}
+
+impl<K, V, S> Iterator for IntoIter<K, V, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        // The map is ours alone, so the slots are emptied as they're taken. The keys left without a
+        // value are freed with the table.
+        let kvs = unsafe { &mut **self._map._raw._kvs.get_mut() };
+        while self._idx < kvs.len() {
+            let idx = self._idx;
+            self._idx += 1;
+            if !is_absent(*kvs._vs.get_mut(idx)) {
+                let k = mem::replace(kvs._ks.get_mut(idx), ptr::null_mut());
+                let v = mem::replace(kvs._vs.get_mut(idx), ptr::null_mut());
+                unsafe {
+                    let (KeyHolder::Key(k), ValueHolder::Value(v)) = (*Box::from_raw(k), *Box::from_raw(v));
+                    return Some((k, v));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The entries `drain()` takes out of a map, borrowed for as long as the guard is held. Dropping it
+/// takes out the rest.
+pub struct Drain<'g, K: Eq + Hash, V: Eq, S: BuildHasher> {
+    _map: &'g NonBlockingHashMap<K, V, S>,
+    _raw: RawIter<'g, BoxedKeys<K>, V, S>,
+    _guard: &'g Guard<'g>,
+}
+
+impl<'g, K: Eq + Hash, V: Eq, S: BuildHasher> Iterator for Drain<'g, K, V, S> {
+    type Item = (&'g K, &'g V);
+
+    fn next(&mut self) -> Option<(&'g K, &'g V)> {
+        for (k, _) in self._raw.by_ref() {
+            let key = unsafe { (*k).key() };
+            // Whatever value the removal takes out is the one yielded, even if it was just replaced
+            if let Some(v) = self._map.remove(key, self._guard) {
+                return Some((key, v));
+            }
+        }
+        None
+    }
+}
+
+impl<'g, K: Eq + Hash, V: Eq, S: BuildHasher> Drop for Drain<'g, K, V, S> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
+// debuging functions
+#[allow(dead_code)]
+unsafe fn print_table<K: Eq + Hash + ToString, V: Eq + ToString, S: BuildHasher>(
+    table: &NonBlockingHashMap<K, V, S>,
+) {
+    print_kvs(table.get_table_nonatomic());
+}
+
+pub fn print_all<K: Eq + Hash + ToString, V: Eq + ToString, S: BuildHasher>(
+    table: &NonBlockingHashMap<K, V, S>,
+) {
+    let _guard = table.guard();
+    let mut kvs = table.get_table_nonatomic();
+    let mut i = 0;
+    while !kvs.is_null() {
+        println!("---Table {}---", i);
+        unsafe { print_kvs(kvs) };
+        i += 1;
+        kvs = unsafe { (*kvs)._chm.get_newkvs_nonatomic() };
+    }
+}
+
+unsafe fn print_kvs<K: Eq + Hash + ToString, V: Eq + ToString>(kvs: *mut KVs<BoxedKeys<K>, V>) {
+    for i in 0..(*kvs).len() {
+        print!(
+            "{}: ({}, ",
+            i,
+            key_to_string((*kvs).get_key_nonatomic_at(i))
+        );
+        print!("{}, ", value_to_string((*kvs).get_value_nonatomic_at(i)));
+        println!("{})", (&(*kvs)._hashes)[i].load(MEMORY_ORDERING));
+    }
+}
+
+unsafe fn key_to_string<K: Eq + Hash + ToString>(key: *mut KeyHolder<K>) -> String {
+    if key == key_tombstone() {
+        return String::from("TOMBSTONE");
+    }
+    match key.as_ref() {
+        None => String::from("EMPTY"),
+        Some(KeyHolder::Key(k)) => k.to_string(),
+    }
+}
+
+unsafe fn value_to_string<V: Eq + ToString>(value: *mut ValueHolder<V>) -> String {
+    if value == tombprime() {
+        return String::from("TOMBPRIME");
+    }
+    if is_in_progress(value) {
+        return String::from("IN PROGRESS");
+    }
+    if is_tombstone(value) {
+        return String::from("TOMBSTONE");
+    }
+    if is_prime(value) {
+        return format!("Prime({})", value_to_string(unprime(value)));
+    }
+    if is_inline(value) {
+        return format!("Inline({:#x})", inline_bits(value));
+    }
+    match value.as_ref() {
+        None => String::from("EMPTY"),
+        Some(v) => v.value().to_string(),
     }
 }
 
@@ -710,16 +1698,20 @@ unsafe fn value_to_string<V: Eq + ToString>(value: *mut ValueHolder<V>) -> Strin
  ****************************************************************************/
 #[cfg(test)]
 mod test {
-    use super::{
-        ConcurrentMap, KVs, NonBlockingHashMap, MEMORY_ORDERING
-    };
+    use super::{BoxedKeys, KVs, KeySlots, NonBlockingHashMap, PutOutcome, MEMORY_ORDERING, REPROBE_LIMIT};
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{HashMap, HashSet};
+    use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
     use std::sync::Arc;
     use std::thread::{sleep, spawn};
     use std::time::Duration;
 
     #[test]
     fn test_kvs_init() {
-        let kvs = KVs::<i32, i32>::new(10);
+        let kvs = KVs::<BoxedKeys<i32>, i32>::new(10);
         for i in 0..kvs._ks.len() {
             assert!(kvs._ks.load(i).is_null());
         }
@@ -733,7 +1725,7 @@ mod test {
         let map = NonBlockingHashMap::<i32, i32>::with_capacity(10);
         assert!(map.capacity() == 16 * 4);
         unsafe {
-            assert!((*map._kvs.load(MEMORY_ORDERING))
+            assert!((*map._raw._kvs.load(MEMORY_ORDERING))
                 ._chm
                 ._newkvs
                 .load(MEMORY_ORDERING)
@@ -744,15 +1736,15 @@ mod test {
     #[test]
     fn test_hashmap_resize() {
         let map1 = NonBlockingHashMap::<i32, i32>::with_capacity(10);
-        let kvs = map1._kvs.load(MEMORY_ORDERING);
+        let kvs = map1._raw._kvs.load(MEMORY_ORDERING);
         unsafe {
-            map1.resize(kvs);
+            map1._raw.resize(kvs);
             assert_eq!(
                 (*(*kvs)._chm._newkvs.load(MEMORY_ORDERING)).len(),
                 16 * 4 * 2
             );
             let kvs = (*kvs)._chm._newkvs.load(MEMORY_ORDERING);
-            map1.resize(kvs);
+            map1._raw.resize(kvs);
             assert_eq!(
                 (*(*kvs)._chm._newkvs.load(MEMORY_ORDERING)).len(),
                 16 * 4 * 4
@@ -761,8 +1753,8 @@ mod test {
         let map2 = NonBlockingHashMap::<i32, i32>::with_capacity(10);
         sleep(Duration::from_millis(2000));
         unsafe {
-            map2.resize(map2._kvs.load(MEMORY_ORDERING));
-            let new_len = (*(*map2._kvs.load(MEMORY_ORDERING))
+            map2._raw.resize(map2._raw._kvs.load(MEMORY_ORDERING));
+            let new_len = (*(*map2._raw._kvs.load(MEMORY_ORDERING))
                 ._chm
                 ._newkvs
                 .load(MEMORY_ORDERING))
@@ -771,20 +1763,47 @@ mod test {
         }
     }
 
-/*
+    // More keys than REPROBE_LIMIT with the same home slot crowd a table even though it's nearly
+    // empty. A table of the same size would be just as crowded, so it must grow instead, however
+    // long ago the last resize was.
+    #[test]
+    fn test_hashmap_resize_crowded_sparse_table() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(16);
+        let guard = map.guard();
+        let len = map.capacity();
+        let home_slot = |n: i32, len: usize| map._raw.hash(&n) as usize & (len - 1);
+        // Keys sharing a home slot here, split between two home slots in a table twice the size
+        let home = home_slot(0, len);
+        let (mut low, mut high): (Vec<i32>, Vec<i32>) = (0..)
+            .filter(|&n| home_slot(n, len) == home)
+            .take(4 * REPROBE_LIMIT)
+            .partition(|&n| home_slot(n, len << 1) == home);
+        low.truncate(REPROBE_LIMIT / 2 + 1);
+        high.truncate(REPROBE_LIMIT / 2);
+        sleep(Duration::from_millis(1100));
+        for &n in low.iter().chain(high.iter()) {
+            map.put(n, n, &guard);
+        }
+        assert_eq!(map.capacity(), len << 1);
+        for &n in low.iter().chain(high.iter()) {
+            assert_eq!(map.get(&n, &guard), Some(&n));
+        }
+    }
+
     #[test]
     fn test_hashmap_single_thread_grow() {
-        let map = ConcurrentMap::with_capacity(10);
+        let map = NonBlockingHashMap::with_capacity(10);
+        let guard = map.guard();
         for n in 0..200_000 {
-            map.as_mut().put(n, n);
+            map.put(n, n, &guard);
         }
         for n in 0..200_000 {
-            assert_eq!(n, *map.as_mut().get(n).unwrap());
+            assert_eq!(n, *map.get(&n, &guard).unwrap());
         }
     }
 
     fn test_hashmap_concurrent(init_size: usize, nthreads: usize, num_keys: usize) {
-        let shared_map = Arc::new(ConcurrentMap::with_capacity(init_size));
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(init_size));
 
         let threads: Vec<_> = (0..nthreads)
             .flat_map(|_| {
@@ -792,9 +1811,8 @@ mod test {
                 let child_map_get = shared_map.clone();
                 let writer = spawn(move || {
                     for i in 0..num_keys {
-                        child_map_put
-                            .as_mut()
-                            .put(format!("key {}", i), format!("value {}", i));
+                        let guard = child_map_put.guard();
+                        child_map_put.put(format!("key {}", i), format!("value {}", i), &guard);
                     }
                 });
 
@@ -803,7 +1821,8 @@ mod test {
                     let mut hit = 0;
                     for i in 0..num_keys {
                         let key = format!("key {}", i);
-                        if let Some(v) = child_map_get.as_mut().get(key) {
+                        let guard = child_map_get.guard();
+                        if let Some(v) = child_map_get.get(&key, &guard) {
                             assert_eq!(*v, format!("value {}", i));
                             hit += 1;
                         }
@@ -827,5 +1846,1228 @@ mod test {
     fn test_hashmap_concurrent_rw_grow() {
         test_hashmap_concurrent(16, 8, 100_000);
     }
-*/
+
+    #[test]
+    fn test_hashmap_remove() {
+        let map = NonBlockingHashMap::<String, String>::new();
+        let guard = map.guard();
+        assert_eq!(map.remove("a", &guard), None);
+        assert_eq!(
+            map.put(String::from("a"), String::from("1"), &guard),
+            PutOutcome::Inserted
+        );
+        assert_eq!(
+            map.put(String::from("a"), String::from("2"), &guard),
+            PutOutcome::Replaced(&String::from("1"))
+        );
+        assert_eq!(map.remove("a", &guard), Some(&String::from("2")));
+        assert_eq!(map.get("a", &guard), None);
+        assert_eq!(map.remove("a", &guard), None);
+        assert_eq!(
+            map.put(String::from("a"), String::from("3"), &guard),
+            PutOutcome::Inserted
+        );
+        assert_eq!(map.get("a", &guard), Some(&String::from("3")));
+    }
+
+    #[test]
+    fn test_hashmap_contains_key() {
+        let map = NonBlockingHashMap::<String, i32>::new();
+        let guard = map.guard();
+        assert!(!map.contains_key("a"));
+        map.put(String::from("a"), 1, &guard);
+        assert!(map.contains_key("a"));
+        map.remove("a", &guard);
+        assert!(!map.contains_key("a"));
+    }
+
+    #[test]
+    fn test_hashmap_remove_during_copy() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(10);
+        let guard = map.guard();
+        for n in 0..40 {
+            map.put(n, n, &guard);
+        }
+        // Start a resize, leaving every slot to be copied lazily
+        let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+        unsafe { map._raw.resize(kvs) };
+        for n in (0..40).step_by(2) {
+            assert_eq!(map.remove(&n, &guard), Some(&n));
+        }
+        for n in 0..40 {
+            assert_eq!(map.get(&n, &guard), if n % 2 == 0 { None } else { Some(&n) });
+        }
+        while map._raw._kvs.load(MEMORY_ORDERING) == kvs {
+            unsafe { map._raw.help_copy(&guard) };
+        }
+        for n in 0..40 {
+            assert_eq!(map.get(&n, &guard), if n % 2 == 0 { None } else { Some(&n) });
+        }
+    }
+
+    #[test]
+    fn test_hashmap_put_if_absent() {
+        let map = NonBlockingHashMap::<i32, String>::new();
+        let guard = map.guard();
+        assert_eq!(map.put_if_absent(1, String::from("a"), &guard), PutOutcome::Inserted);
+        assert_eq!(
+            map.put_if_absent(1, String::from("b"), &guard),
+            PutOutcome::Rejected(Some(&String::from("a")))
+        );
+        assert_eq!(map.get(&1, &guard), Some(&String::from("a")));
+        map.remove(&1, &guard);
+        assert_eq!(map.put_if_absent(1, String::from("c"), &guard), PutOutcome::Inserted);
+        assert_eq!(map.get(&1, &guard), Some(&String::from("c")));
+    }
+
+    #[test]
+    fn test_hashmap_put_if_absent_during_copy() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(10);
+        let guard = map.guard();
+        for n in 0..40 {
+            map.put(n, n, &guard);
+        }
+        let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+        unsafe { map._raw.resize(kvs) };
+        for n in 0..80 {
+            let expected = if n < 40 {
+                PutOutcome::Rejected(Some(&n))
+            } else {
+                PutOutcome::Inserted
+            };
+            assert_eq!(map.put_if_absent(n, -n, &guard), expected);
+        }
+        while map._raw._kvs.load(MEMORY_ORDERING) == kvs {
+            unsafe { map._raw.help_copy(&guard) };
+        }
+        for n in 0..80 {
+            assert_eq!(map.get(&n, &guard), Some(&if n < 40 { n } else { -n }));
+        }
+    }
+
+    // All threads race to put their own value for the same keys: exactly one of them wins each key
+    // and every loser is told the winning value.
+    #[test]
+    fn test_hashmap_concurrent_put_if_absent() {
+        let nthreads = 8;
+        let num_keys = 20_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    (0..num_keys)
+                        .map(|i| match map.put_if_absent(i, t, &map.guard()) {
+                            PutOutcome::Inserted => t,
+                            PutOutcome::Rejected(Some(winner)) => *winner,
+                            outcome => panic!("unexpected {:?}", outcome),
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let seen: Vec<Vec<usize>> = threads
+            .into_iter()
+            .map(|t| t.join().expect("Error joining"))
+            .collect();
+        let guard = shared_map.guard();
+        for i in 0..num_keys {
+            let winner = *shared_map.get(&i, &guard).unwrap();
+            assert!(seen.iter().all(|s| s[i] == winner));
+            assert_eq!(1, seen.iter().enumerate().filter(|(t, s)| s[i] == *t).count());
+        }
+    }
+
+    #[test]
+    fn test_hashmap_get_with() {
+        let map = NonBlockingHashMap::<i32, String>::new();
+        assert_eq!(map.get_with(&1, |v| v.len()), None);
+        map.put(1, String::from("abc"), &map.guard());
+        assert_eq!(map.get_with(&1, |v| v.len()), Some(3));
+    }
+
+    // A value read under a guard outlives its removal from the map until the guard is dropped
+    #[test]
+    fn test_hashmap_guarded_get_outlives_replace() {
+        let map = NonBlockingHashMap::<i32, String>::new();
+        map.put(1, String::from("a"), &map.guard());
+        let guard = map.guard();
+        let v = map.get(&1, &guard).unwrap();
+        for n in 0..1000 {
+            map.put(1, n.to_string(), &map.guard());
+        }
+        assert_eq!(v, "a");
+    }
+
+    #[test]
+    #[should_panic(expected = "guard is from another map")]
+    fn test_hashmap_get_with_foreign_guard() {
+        let map1 = NonBlockingHashMap::<i32, i32>::new();
+        let map2 = NonBlockingHashMap::<i32, i32>::new();
+        map1.get(&1, &map2.guard());
+    }
+
+    #[test]
+    fn test_hashmap_replace() {
+        let map = NonBlockingHashMap::<i32, String>::new();
+        let guard = map.guard();
+        assert_eq!(map.replace(&1, String::from("a"), &guard), PutOutcome::Rejected(None));
+        assert_eq!(map.get(&1, &guard), None);
+        map.put(1, String::from("a"), &guard);
+        assert_eq!(
+            map.replace(&1, String::from("b"), &guard),
+            PutOutcome::Replaced(&String::from("a"))
+        );
+        assert_eq!(map.get(&1, &guard), Some(&String::from("b")));
+        map.remove(&1, &guard);
+        assert_eq!(map.replace(&1, String::from("c"), &guard), PutOutcome::Rejected(None));
+        assert_eq!(map.get(&1, &guard), None);
+    }
+
+    #[test]
+    fn test_hashmap_compare_and_replace() {
+        let map = NonBlockingHashMap::<i32, String>::new();
+        let guard = map.guard();
+        let a = String::from("a");
+        let b = String::from("b");
+        assert_eq!(
+            map.compare_and_replace(&1, &a, b.clone(), &guard),
+            PutOutcome::Rejected(None)
+        );
+        assert_eq!(map.get(&1, &guard), None);
+        map.put(1, a.clone(), &guard);
+        assert_eq!(
+            map.compare_and_replace(&1, &b, b.clone(), &guard),
+            PutOutcome::Rejected(Some(&a))
+        );
+        assert_eq!(
+            map.compare_and_replace(&1, &a, b.clone(), &guard),
+            PutOutcome::Replaced(&a)
+        );
+        assert_eq!(map.get(&1, &guard), Some(&b));
+        map.remove(&1, &guard);
+        assert_eq!(
+            map.compare_and_replace(&1, &b, a.clone(), &guard),
+            PutOutcome::Rejected(None)
+        );
+    }
+
+    // Increments shared counters with compare_and_replace retry loops while the table grows. None
+    // of the increments may be lost.
+    #[test]
+    fn test_hashmap_concurrent_compare_and_replace() {
+        let nthreads = 8;
+        let num_keys = 1_000;
+        let increments = 10;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    for _ in 0..increments {
+                        for i in 0..num_keys {
+                            let guard = map.guard();
+                            map.put_if_absent(i, 0, &guard);
+                            let mut current = *map.get(&i, &guard).unwrap();
+                            while let PutOutcome::Rejected(found) =
+                                map.compare_and_replace(&i, &current, current + 1, &guard)
+                            {
+                                current = *found.unwrap();
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = shared_map.guard();
+        for i in 0..num_keys {
+            assert_eq!(shared_map.get(&i, &guard), Some(&(nthreads * increments)));
+        }
+    }
+
+    #[test]
+    fn test_hashmap_compute() {
+        let map = NonBlockingHashMap::<String, i32>::new();
+        let guard = map.guard();
+        let a = String::from("a");
+        assert_eq!(map.compute(&a, |v| v.map(|v| v + 1), &guard), None);
+        assert!(!map.contains_key("a"));
+        assert_eq!(map.compute(&a, |v| Some(v.map_or(1, |v| v + 1)), &guard), Some(&1));
+        assert_eq!(map.compute(&a, |v| Some(v.map_or(1, |v| v + 1)), &guard), Some(&2));
+        assert_eq!(map.compute(&a, |_| None, &guard), None);
+        assert_eq!(map.get("a", &guard), None);
+
+        assert_eq!(map.compute_if_present("a", |v| Some(v + 1), &guard), None);
+        assert_eq!(map.compute_if_absent(&a, || 10, &guard), &10);
+        assert_eq!(map.compute_if_absent(&a, || panic!("present already"), &guard), &10);
+        assert_eq!(map.compute_if_present("a", |v| Some(v + 1), &guard), Some(&11));
+        assert_eq!(map.compute_if_present("a", |_| None, &guard), None);
+        assert!(!map.contains_key("a"));
+
+        assert_eq!(map.merge(&a, 5, |v, d| Some(v + d), &guard), Some(&5));
+        assert_eq!(map.merge(&a, 5, |v, d| Some(v + d), &guard), Some(&10));
+        assert_eq!(map.merge(&a, 5, |_, _| None, &guard), None);
+        assert!(!map.contains_key("a"));
+        assert!(map.is_empty());
+    }
+
+    // Counters incremented with compute() and lists appended to with merge() from all threads at
+    // once, while the table grows from its smallest size. Not a single update may be lost, however
+    // often the closures are retried.
+    #[test]
+    fn test_hashmap_concurrent_compute() {
+        let nthreads = 8;
+        let num_keys = 1_000;
+        let increments = 10;
+        let shared_map = Arc::new(NonBlockingHashMap::<usize, Vec<usize>>::with_capacity(16));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                let calls = calls.clone();
+                spawn(move || {
+                    for round in 0..increments {
+                        for i in 0..num_keys {
+                            let guard = map.guard();
+                            map.compute(
+                                &i,
+                                |v| {
+                                    calls.fetch_add(1, Ordering::SeqCst);
+                                    let mut v = v.cloned().unwrap_or_default();
+                                    v.push(t * increments + round);
+                                    Some(v)
+                                },
+                                &guard,
+                            );
+                            map.merge(&(num_keys + i), vec![t], |v, d| Some([&v[..], d].concat()), &guard);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        assert!(calls.load(Ordering::SeqCst) >= nthreads * num_keys * increments);
+        let guard = shared_map.guard();
+        for i in 0..num_keys {
+            let mut updates = shared_map.get(&i, &guard).unwrap().clone();
+            updates.sort();
+            assert_eq!(updates, (0..nthreads * increments).collect::<Vec<_>>());
+            assert_eq!(shared_map.get(&(num_keys + i), &guard).unwrap().len(), nthreads * increments);
+        }
+    }
+
+    // Threads race to fill the same keys: all of them get the value which got in first
+    #[test]
+    fn test_hashmap_concurrent_compute_if_absent() {
+        let nthreads = 8;
+        let num_keys = 10_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    (0..num_keys)
+                        .map(|i| *map.compute_if_absent(&i, || t, &map.guard()))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let seen: Vec<Vec<usize>> = threads
+            .into_iter()
+            .map(|t| t.join().expect("Error joining"))
+            .collect();
+        let guard = shared_map.guard();
+        for i in 0..num_keys {
+            let winner = *shared_map.get(&i, &guard).unwrap();
+            assert!(seen.iter().all(|s| s[i] == winner));
+        }
+    }
+
+    #[test]
+    fn test_hashmap_get_or_insert_with() {
+        let map = NonBlockingHashMap::<i32, String>::new();
+        let guard = map.guard();
+        assert_eq!(map.get_or_insert_with(&1, || String::from("a"), &guard), "a");
+        assert_eq!(map.get_or_insert_with(&1, || String::from("b"), &guard), "a");
+        assert_eq!(map.get_or_insert_with_once(&1, || panic!("present already"), &guard), "a");
+        assert_eq!(map.get_or_insert_with_once(&2, || String::from("c"), &guard), "c");
+        assert_eq!(map.get(&2, &guard), Some(&String::from("c")));
+        assert_eq!(map.len(), 2);
+    }
+
+    // However many threads ask for the same keys at once, each key's value is computed only once
+    // and all of them get it
+    #[test]
+    fn test_hashmap_concurrent_get_or_insert_with_once() {
+        let nthreads = 8;
+        let num_keys = 2_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+        let computed = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                let computed = computed.clone();
+                spawn(move || {
+                    (0..num_keys)
+                        .map(|i| {
+                            let compute = || {
+                                computed.fetch_add(1, Ordering::SeqCst);
+                                sleep(Duration::from_micros(10));
+                                t
+                            };
+                            *map.get_or_insert_with_once(&i, compute, &map.guard())
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let seen: Vec<Vec<usize>> = threads
+            .into_iter()
+            .map(|t| t.join().expect("Error joining"))
+            .collect();
+        assert_eq!(computed.load(Ordering::SeqCst), num_keys);
+        let guard = shared_map.guard();
+        for i in 0..num_keys {
+            let winner = *shared_map.get(&i, &guard).unwrap();
+            assert!(seen.iter().all(|s| s[i] == winner));
+        }
+    }
+
+    // While a value is in progress the key has no value to anyone else, through every resize,
+    // and a put meanwhile wins over the computed value
+    #[test]
+    fn test_hashmap_get_or_insert_with_once_in_progress() {
+        let shared_map = Arc::new(NonBlockingHashMap::<i32, i32>::with_capacity(16));
+        let (started_tx, started_rx) = channel();
+        let (finish_tx, finish_rx) = channel::<()>();
+        let map = shared_map.clone();
+        let computing = spawn(move || {
+            let compute = || {
+                started_tx.send(()).unwrap();
+                finish_rx.recv().unwrap();
+                -1
+            };
+            *map.get_or_insert_with_once(&0, compute, &map.guard())
+        });
+        started_rx.recv().unwrap();
+        let map = shared_map.clone();
+        let waiting = spawn(move || *map.get_or_insert_with_once(&0, || panic!("in progress already"), &map.guard()));
+
+        let guard = shared_map.guard();
+        let capacity = shared_map.capacity();
+        for n in 1..1000 {
+            shared_map.put(n, n, &guard);
+        }
+        assert!(shared_map.capacity() > capacity);
+        assert_eq!(shared_map.get(&0, &guard), None);
+        assert_eq!(shared_map.replace(&0, 0, &guard), PutOutcome::Rejected(None));
+        assert_eq!(shared_map.remove(&0, &guard), None);
+        assert_eq!(shared_map.len(), 999);
+        assert!(shared_map.keys(&guard).all(|k| *k != 0));
+        finish_tx.send(()).unwrap();
+        assert_eq!(computing.join().unwrap(), -1);
+        assert_eq!(waiting.join().unwrap(), -1);
+        assert_eq!(shared_map.get(&0, &guard), Some(&-1));
+        assert_eq!(shared_map.len(), 1000);
+
+        let (started_tx, started_rx) = channel();
+        let (finish_tx, finish_rx) = channel::<()>();
+        let map = shared_map.clone();
+        let computing = spawn(move || {
+            let compute = || {
+                started_tx.send(()).unwrap();
+                finish_rx.recv().unwrap();
+                -1
+            };
+            *map.get_or_insert_with_once(&1000, compute, &map.guard())
+        });
+        started_rx.recv().unwrap();
+        assert_eq!(shared_map.put_if_absent(1000, 1000, &guard), PutOutcome::Inserted);
+        finish_tx.send(()).unwrap();
+        assert_eq!(computing.join().unwrap(), 1000);
+        assert_eq!(shared_map.get(&1000, &guard), Some(&1000));
+    }
+
+    #[test]
+    fn test_hashmap_get_or_insert_with_once_panicking() {
+        let map = NonBlockingHashMap::<i32, i32>::new();
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            map.get_or_insert_with_once(&1, || panic!("no value"), &map.guard());
+        }));
+        assert!(panicked.is_err());
+        let guard = map.guard();
+        // Nobody is left waiting for a value which will never come
+        assert_eq!(map.get_or_insert_with_once(&1, || 1, &guard), &1);
+    }
+
+    #[test]
+    fn test_hashmap_single_thread_grow_and_shrink() {
+        let map = NonBlockingHashMap::with_capacity(10);
+        let guard = map.guard();
+        for n in 0..100_000 {
+            map.put(n, n, &guard);
+        }
+        for n in 0..100_000 {
+            assert_eq!(Some(&n), map.remove(&n, &guard));
+        }
+        for n in 0..100_000 {
+            assert_eq!(None, map.get(&n, &guard));
+        }
+    }
+
+    // Each thread puts and removes keys of its own while all the others do the same, growing the
+    // table from its minimum size through many promotions.
+    #[test]
+    fn test_hashmap_concurrent_remove_grow() {
+        let nthreads = 8;
+        let num_keys = 20_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    for i in 0..num_keys {
+                        let guard = map.guard();
+                        map.put(format!("key {} {}", t, i), i, &guard);
+                        if i % 3 == 0 {
+                            assert_eq!(Some(&i), map.remove(&format!("key {} {}", t, i), &guard));
+                        }
+                    }
+                    for i in 0..num_keys {
+                        let expected = if i % 3 == 0 { None } else { Some(&i) };
+                        assert_eq!(expected, map.get(&format!("key {} {}", t, i), &map.guard()));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+    }
+
+    // Writers and removers race on the same keys; whatever is left must be a value some writer put.
+    #[test]
+    fn test_hashmap_concurrent_remove_racing_put() {
+        let nthreads = 4;
+        let num_keys = 10_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+
+        let threads: Vec<_> = (0..nthreads)
+            .flat_map(|t| {
+                let child_map_put = shared_map.clone();
+                let child_map_remove = shared_map.clone();
+                let writer = spawn(move || {
+                    for i in 0..num_keys {
+                        child_map_put.put(i, i * 10 + t, &child_map_put.guard());
+                    }
+                });
+                let remover = spawn(move || {
+                    for i in 0..num_keys {
+                        if let Some(v) = child_map_remove.remove(&i, &child_map_remove.guard()) {
+                            assert_eq!(i, *v / 10);
+                        }
+                    }
+                });
+                vec![writer, remover]
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = shared_map.guard();
+        for i in 0..num_keys {
+            if let Some(v) = shared_map.get(&i, &guard) {
+                assert_eq!(i, *v / 10);
+                assert!(*v % 10 < nthreads);
+            }
+        }
+    }
+
+    // Counts its live instances, so that a key freed twice or never shows up in LIVE_KEYS
+    #[derive(PartialEq, Eq, Hash)]
+    struct Tracked(usize);
+
+    static LIVE_KEYS: AtomicIsize = AtomicIsize::new(0);
+
+    impl Tracked {
+        fn new(n: usize) -> Tracked {
+            LIVE_KEYS.fetch_add(1, Ordering::SeqCst);
+            Tracked(n)
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            LIVE_KEYS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Many threads growing the map from its minimum size copy the same slots at once, through
+    // several tables in flight. Each key must still be freed once, by the last table it's in.
+    #[test]
+    fn test_hashmap_concurrent_grow_frees_every_key_once() {
+        let nthreads = 32;
+        let num_keys = 500;
+        for _ in 0..10 {
+            let shared_map = Arc::new(NonBlockingHashMap::new());
+            let threads: Vec<_> = (0..nthreads)
+                .map(|t| {
+                    let map = shared_map.clone();
+                    spawn(move || {
+                        for i in 0..num_keys {
+                            map.put(Tracked::new(i * nthreads + t), i, &map.guard());
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().expect("Error joining");
+            }
+            drop(shared_map);
+            assert_eq!(LIVE_KEYS.load(Ordering::SeqCst), 0);
+        }
+    }
+
+    // Helpers keep calling help_copy while writers grow maps from their minimum size, so some of
+    // them see a table promoted in the middle of the call.
+    #[test]
+    fn test_hashmap_help_copy_racing_promotion() {
+        let nwriters = 2;
+        let nhelpers = 4;
+        let num_keys = 1_000;
+        for _ in 0..40 {
+            let shared_map = Arc::new(NonBlockingHashMap::new());
+            let done = Arc::new(AtomicBool::new(false));
+            let helpers: Vec<_> = (0..nhelpers)
+                .map(|_| {
+                    let map = shared_map.clone();
+                    let done = done.clone();
+                    spawn(move || {
+                        while !done.load(Ordering::SeqCst) {
+                            let guard = map.guard();
+                            unsafe { map._raw.help_copy(&guard) };
+                        }
+                    })
+                })
+                .collect();
+            let writers: Vec<_> = (0..nwriters)
+                .map(|t| {
+                    let map = shared_map.clone();
+                    spawn(move || {
+                        for i in 0..num_keys {
+                            map.put(i * nwriters + t, i, &map.guard());
+                        }
+                    })
+                })
+                .collect();
+            for t in writers {
+                t.join().expect("Error joining");
+            }
+            done.store(true, Ordering::SeqCst);
+            for t in helpers {
+                t.join().expect("Error joining");
+            }
+            let guard = shared_map.guard();
+            for i in 0..num_keys * nwriters {
+                assert_eq!(shared_map.get(&i, &guard), Some(&(i / nwriters)));
+            }
+        }
+    }
+
+    // Counts the hashers built, one for each key hashed
+    #[derive(Clone, Default)]
+    struct CountingBuildHasher(Arc<AtomicUsize>);
+
+    impl BuildHasher for CountingBuildHasher {
+        type Hasher = DefaultHasher;
+
+        fn build_hasher(&self) -> DefaultHasher {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            DefaultHasher::new()
+        }
+    }
+
+    #[test]
+    fn test_hashmap_with_hasher() {
+        let hasher = CountingBuildHasher::default();
+        let map = NonBlockingHashMap::with_capacity_and_hasher(10, hasher.clone());
+        let guard = map.guard();
+        for n in 0..1000 {
+            map.put(n, n, &guard);
+        }
+        // Every put hashes its key once. Copying into the bigger tables reuses the stored hashes.
+        assert!(map.capacity() > 10 * 4);
+        assert_eq!(hasher.0.load(Ordering::SeqCst), 1000);
+        for n in 0..1000 {
+            assert_eq!(map.get(&n, &guard), Some(&n));
+        }
+        assert_eq!(hasher.0.load(Ordering::SeqCst), 2000);
+    }
+
+    #[test]
+    fn test_hashmap_copy_reuses_hashes() {
+        let hasher = CountingBuildHasher::default();
+        let map = NonBlockingHashMap::with_capacity_and_hasher(10, hasher.clone());
+        let guard = map.guard();
+        for n in 0..40 {
+            map.put(n, n, &guard);
+        }
+        let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+        unsafe { map._raw.resize(kvs) };
+        while map._raw._kvs.load(MEMORY_ORDERING) == kvs {
+            unsafe { map._raw.help_copy(&guard) };
+        }
+        assert_eq!(hasher.0.load(Ordering::SeqCst), 40);
+    }
+
+    #[test]
+    fn test_hashmap_default_hasher() {
+        let map: NonBlockingHashMap<i32, i32, BuildHasherDefault<DefaultHasher>> = Default::default();
+        let guard = map.guard();
+        for n in 0..1000 {
+            map.put(n, n, &guard);
+        }
+        for n in 0..1000 {
+            assert_eq!(map.get(&n, &guard), Some(&n));
+        }
+    }
+
+    // Keys which count their comparisons, hashed so that groups of 8 of them share a probe
+    // sequence while their full hashes all differ
+    #[derive(Debug)]
+    struct Collider(u64);
+
+    static COLLIDER_EQS: AtomicUsize = AtomicUsize::new(0);
+
+    impl PartialEq for Collider {
+        fn eq(&self, other: &Collider) -> bool {
+            COLLIDER_EQS.fetch_add(1, Ordering::SeqCst);
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Collider {}
+
+    impl Hash for Collider {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            state.write_u64((self.0 / 8 * 16) | (self.0 << 32));
+        }
+    }
+
+    #[derive(Default)]
+    struct IdentityHasher(u64);
+
+    impl Hasher for IdentityHasher {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for &b in bytes {
+                self.0 = (self.0 << 8) | u64::from(b);
+            }
+        }
+
+        fn write_u64(&mut self, n: u64) {
+            self.0 = n;
+        }
+    }
+
+    #[test]
+    fn test_hashmap_hashes_reject_colliding_keys() {
+        let map: NonBlockingHashMap<Collider, u64, BuildHasherDefault<IdentityHasher>> =
+            NonBlockingHashMap::with_capacity_and_hasher(1024, Default::default());
+        let guard = map.guard();
+        for n in 0..512 {
+            map.put(Collider(n), n, &guard);
+        }
+        // Only the key itself is compared, not the others before it in the probe sequence
+        COLLIDER_EQS.store(0, Ordering::SeqCst);
+        for n in 0..512 {
+            assert_eq!(map.get(&Collider(n), &guard), Some(&n));
+        }
+        assert_eq!(COLLIDER_EQS.load(Ordering::SeqCst), 512);
+        COLLIDER_EQS.store(0, Ordering::SeqCst);
+        for n in 0..512 {
+            map.replace(&Collider(n), n + 1, &guard);
+        }
+        assert_eq!(COLLIDER_EQS.load(Ordering::SeqCst), 512);
+    }
+
+    #[test]
+    fn test_hashmap_iter() {
+        let map = NonBlockingHashMap::<i32, String>::with_capacity(16);
+        let guard = map.guard();
+        assert_eq!(map.iter(&guard).next(), None);
+        for n in 0..1000 {
+            map.put(n, n.to_string(), &guard);
+        }
+        for n in (0..1000).step_by(3) {
+            map.remove(&n, &guard);
+        }
+        // Removed keys leave tombstones behind, which are never yielded
+        let expected: HashMap<i32, String> = (0..1000).filter(|n| n % 3 != 0).map(|n| (n, n.to_string())).collect();
+        let entries: HashMap<i32, String> = map.iter(&guard).map(|(k, v)| (*k, v.clone())).collect();
+        assert_eq!(entries, expected);
+        assert_eq!(map.iter(&guard).count(), expected.len());
+        assert_eq!(map.keys(&guard).copied().collect::<HashSet<_>>(), expected.keys().copied().collect());
+        let mut values: Vec<&String> = map.values(&guard).collect();
+        values.sort();
+        let mut expected_values: Vec<&String> = expected.values().collect();
+        expected_values.sort();
+        assert_eq!(values, expected_values);
+    }
+
+    // A resize begun during the walk primes the slots of the table being walked, and the values
+    // are looked up in the new table instead
+    #[test]
+    fn test_hashmap_iter_across_resize() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(10);
+        let guard = map.guard();
+        for n in 0..40 {
+            map.put(n, n, &guard);
+        }
+        let mut iter = map.iter(&guard);
+        let first = iter.next().map(|(k, v)| (*k, *v)).unwrap();
+        let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+        unsafe { map._raw.resize(kvs) };
+        for n in (0..40).step_by(2) {
+            map.put(n, -n, &guard);
+        }
+        let mut entries: HashMap<i32, i32> = iter.map(|(k, v)| (*k, *v)).collect();
+        assert!(!entries.contains_key(&first.0));
+        entries.insert(first.0, first.1);
+        assert_eq!(entries.len(), 40);
+        for n in 0..40 {
+            if n != first.0 {
+                assert_eq!(entries[&n], if n % 2 == 0 { -n } else { n });
+            }
+        }
+    }
+
+    // A walk over a table left being copied only copies the slots of the buckets it has got to,
+    // and the copy is over once it has got to all of them
+    #[test]
+    fn test_hashmap_iter_during_copy() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(1000);
+        let guard = map.guard();
+        for n in 0..1000 {
+            map.put(n, n, &guard);
+        }
+        let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+        unsafe { map._raw.resize(kvs) };
+        let mut iter = map.iter(&guard);
+        let (k, v) = iter.next().unwrap();
+        assert_eq!(k, v);
+        // Only as many buckets as it took to come to a key, a few at most
+        let (copy_done, oldlen) = unsafe { ((*kvs)._chm._copy_done.load(MEMORY_ORDERING), (*kvs).len()) };
+        assert!(copy_done > 0 && copy_done < oldlen / 8, "{} of {} slots copied", copy_done, oldlen);
+        let mut keys: Vec<i32> = iter.map(|(k, _)| *k).chain(Some(*k)).collect();
+        keys.sort();
+        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+        assert_ne!(map._raw._kvs.load(MEMORY_ORDERING), kvs);
+    }
+
+    // The probe from a home slot wraps round a table with fewer slots than REPROBE_LIMIT, and a
+    // slot is still only visited once for it
+    #[test]
+    fn test_hashmap_iter_smallest_table() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(1);
+        assert!(map.capacity() < REPROBE_LIMIT);
+        let guard = map.guard();
+        for n in 0..4 {
+            map.put(n, n, &guard);
+        }
+        let mut keys: Vec<i32> = map.keys(&guard).copied().collect();
+        keys.sort();
+        assert_eq!(keys, (0..4).collect::<Vec<_>>());
+    }
+
+    // A thread copying a slot stores the key's hash only after the key, and another thread may
+    // have put the value in meanwhile. Such a key is still yielded and scanned.
+    #[test]
+    fn test_hashmap_iter_key_without_hash() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(100);
+        let guard = map.guard();
+        for n in 0..100 {
+            map.put(n, n, &guard);
+        }
+        let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+        for hash in unsafe { &(*kvs)._hashes }.iter() {
+            hash.store(0, MEMORY_ORDERING);
+        }
+        let mut keys: Vec<i32> = map.keys(&guard).copied().collect();
+        keys.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+        let mut keys = scan_all(&map, 3);
+        keys.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+    }
+
+    // While other threads put, replace and remove keys of their own, and so resize the table over
+    // and over, a walk yields every key which is in the map all along exactly once, and no key
+    // which has been removed for good before it began.
+    #[test]
+    fn test_hashmap_iter_weakly_consistent() {
+        let nthreads = 4;
+        let num_keys = 20_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+        for i in 0..1000 {
+            shared_map.put(i, i, &shared_map.guard());
+        }
+        for i in 1000..2000 {
+            shared_map.put(i, i, &shared_map.guard());
+            shared_map.remove(&i, &shared_map.guard());
+        }
+
+        let writers: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    let base = 10_000 + t * num_keys;
+                    for i in base..base + num_keys {
+                        let guard = map.guard();
+                        map.put(i, i, &guard);
+                        map.replace(&(i % 1000), i % 1000, &guard);
+                        if i % 2 == 0 {
+                            map.remove(&i, &guard);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..20 {
+            let guard = shared_map.guard();
+            let mut seen = HashSet::new();
+            for (k, v) in shared_map.iter(&guard) {
+                assert!(seen.insert(*k), "{} yielded twice", k);
+                assert_eq!(k, v);
+            }
+            assert!((0..1000).all(|i| seen.contains(&i)));
+            assert!(!(1000..2000).any(|i| seen.contains(&i)));
+        }
+        for t in writers {
+            t.join().expect("Error joining");
+        }
+    }
+
+    // Entries still in an older table are moved out too, and removed keys aren't
+    #[test]
+    fn test_hashmap_into_iter_across_resize() {
+        let map = NonBlockingHashMap::<String, String>::with_capacity(10);
+        {
+            let guard = map.guard();
+            for n in 0..40 {
+                map.put(n.to_string(), format!("value {}", n), &guard);
+            }
+            for n in (0..40).step_by(4) {
+                map.remove(n.to_string().as_str(), &guard);
+            }
+            let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+            unsafe { map._raw.resize(kvs) };
+            map.put(String::from("1"), String::from("replaced"), &guard);
+        }
+        let mut expected: HashMap<String, String> =
+            (0..40).filter(|n| n % 4 != 0).map(|n| (n.to_string(), format!("value {}", n))).collect();
+        expected.insert(String::from("1"), String::from("replaced"));
+        let entries: Vec<(String, String)> = map.into_iter().collect();
+        assert_eq!(entries.len(), expected.len());
+        assert_eq!(entries.into_iter().collect::<HashMap<_, _>>(), expected);
+    }
+
+    #[test]
+    fn test_hashmap_into_std() {
+        let map = NonBlockingHashMap::<i32, Vec<i32>>::new();
+        let guard = map.guard();
+        for n in 0..1000 {
+            map.put(n, vec![n], &guard);
+        }
+        drop(guard);
+        let entries = map.into_std();
+        assert_eq!(entries.len(), 1000);
+        assert!((0..1000).all(|n| entries[&n] == vec![n]));
+    }
+
+    #[test]
+    fn test_hashmap_drain() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(16);
+        let guard = map.guard();
+        for n in 0..1000 {
+            map.put(n, -n, &guard);
+        }
+        let drained: HashMap<i32, i32> = map.drain(&guard).take(10).map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(drained.len(), 10);
+        assert!(drained.iter().all(|(k, v)| *v == -k));
+        // The rest is taken out once the drain is dropped
+        assert!(map.is_empty());
+        assert_eq!(map.iter(&guard).count(), 0);
+        map.put(1, 1, &guard);
+        assert_eq!(map.get(&1, &guard), Some(&1));
+    }
+
+    // While writers put keys of their own, each key ends up either drained exactly once or left in
+    // the map
+    #[test]
+    fn test_hashmap_concurrent_drain() {
+        let nthreads = 4;
+        let num_keys = 20_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+        let writers: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    for i in t * num_keys..(t + 1) * num_keys {
+                        map.put(i, i, &map.guard());
+                    }
+                })
+            })
+            .collect();
+        let mut drained = HashSet::new();
+        for _ in 0..20 {
+            let guard = shared_map.guard();
+            for (k, v) in shared_map.drain(&guard) {
+                assert_eq!(k, v);
+                assert!(drained.insert(*k), "{} drained twice", k);
+            }
+        }
+        for t in writers {
+            t.join().expect("Error joining");
+        }
+        let guard = shared_map.guard();
+        for (k, _) in shared_map.iter(&guard) {
+            assert!(drained.insert(*k), "{} both drained and left", k);
+        }
+        assert_eq!(drained.len(), nthreads * num_keys);
+    }
+
+    fn scan_all(map: &NonBlockingHashMap<i32, i32>, count: usize) -> Vec<i32> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let guard = map.guard();
+            let (next, entries) = map.scan(cursor, count, &guard);
+            keys.extend(entries.into_iter().map(|(k, _)| *k));
+            if next == 0 {
+                return keys;
+            }
+            cursor = next;
+        }
+    }
+
+    #[test]
+    fn test_hashmap_scan() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(100);
+        let guard = map.guard();
+        assert_eq!(map.scan(0, map.capacity(), &guard), (0, vec![]));
+        for n in 0..100 {
+            map.put(n, n, &map.guard());
+        }
+        for count in [1, 7, 1000] {
+            let mut keys = scan_all(&map, count);
+            keys.sort();
+            assert_eq!(keys, (0..100).collect::<Vec<_>>());
+        }
+        // Every bucket in one batch
+        let (cursor, entries) = map.scan(0, map.capacity(), &guard);
+        assert_eq!(cursor, 0);
+        assert_eq!(entries.len(), 100);
+    }
+
+    // The probe from a home slot wraps round a table with fewer slots than REPROBE_LIMIT, and a
+    // slot is still only visited once for it
+    #[test]
+    fn test_hashmap_scan_smallest_table() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(1);
+        assert!(map.capacity() < REPROBE_LIMIT);
+        let guard = map.guard();
+        map.put(1, 1, &guard);
+        assert_eq!(map.scan(0, map.capacity(), &guard), (0, vec![(&1, &1)]));
+        for n in 2..5 {
+            map.put(n, n, &guard);
+        }
+        for count in [1, 3, map.capacity()] {
+            let mut keys = scan_all(&map, count);
+            keys.sort();
+            assert_eq!(keys, (1..5).collect::<Vec<_>>());
+        }
+    }
+
+    // A scan over a table left being copied only copies the slots of the buckets it has got to,
+    // and the copy is over once it has got to all of them
+    #[test]
+    fn test_hashmap_scan_during_copy() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(1000);
+        let guard = map.guard();
+        for n in 0..1000 {
+            map.put(n, n, &guard);
+        }
+        let kvs = map._raw._kvs.load(MEMORY_ORDERING);
+        unsafe { map._raw.resize(kvs) };
+        let (_, entries) = map.scan(0, 4, &guard);
+        assert!(entries.iter().all(|(k, v)| k == v));
+        let copy_done = unsafe { (*kvs)._chm._copy_done.load(MEMORY_ORDERING) };
+        assert!(copy_done > 0 && copy_done <= 4 * REPROBE_LIMIT);
+        let mut keys = scan_all(&map, 7);
+        keys.sort();
+        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+        assert_ne!(map._raw._kvs.load(MEMORY_ORDERING), kvs);
+    }
+
+    // The table grows between batches, several times over. The keys put before the scan are each
+    // returned once, whichever batch they fall in.
+    #[test]
+    fn test_hashmap_scan_across_resizes() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(16);
+        for n in 0..100 {
+            map.put(n, n, &map.guard());
+        }
+        let capacity = map.capacity();
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        let mut next_key = 100;
+        loop {
+            let guard = map.guard();
+            let (next, entries) = map.scan(cursor, 3, &guard);
+            seen.extend(entries.into_iter().map(|(k, _)| *k).filter(|k| *k < 100));
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+            if next_key < 10_000 {
+                for _ in 0..500 {
+                    map.put(next_key, next_key, &guard);
+                    next_key += 1;
+                }
+            }
+        }
+        assert!(map.capacity() > capacity * 4);
+        seen.sort();
+        assert_eq!(seen, (0..100).collect::<Vec<_>>());
+    }
+
+    // Other threads put and remove keys of their own, resizing the table over and over, while the
+    // scan goes on in small batches
+    #[test]
+    fn test_hashmap_concurrent_scan() {
+        let nthreads = 4;
+        let num_keys = 20_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+        for n in 0..1000 {
+            shared_map.put(n, n, &shared_map.guard());
+        }
+        let writers: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    let base = 10_000 + t * num_keys;
+                    for i in base..base + num_keys {
+                        let guard = map.guard();
+                        map.put(i, i, &guard);
+                        if i % 2 == 0 {
+                            map.remove(&i, &guard);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..5 {
+            let keys = scan_all(&shared_map, 2);
+            let mut seen = HashSet::new();
+            for k in &keys {
+                assert!(seen.insert(*k), "{} returned twice", k);
+            }
+            assert!((0..1000).all(|n| seen.contains(&n)));
+        }
+        for t in writers {
+            t.join().expect("Error joining");
+        }
+    }
+
+    // The count is kept by the map rather than by each table, so it carries over as the table grows
+    #[test]
+    fn test_hashmap_len() {
+        let map = NonBlockingHashMap::<i32, i32>::with_capacity(16);
+        let guard = map.guard();
+        assert!(map.is_empty());
+        for n in 0..10_000 {
+            map.put(n, n, &guard);
+            map.put(n, -n, &guard);
+        }
+        assert!(map.capacity() >= 10_000);
+        assert_eq!(map.len(), 10_000);
+        for n in (0..10_000).step_by(2) {
+            map.remove(&n, &guard);
+            map.remove(&n, &guard);
+        }
+        map.remove(&-1, &guard);
+        map.replace(&0, 0, &guard);
+        map.put_if_absent(1, 1, &guard);
+        assert_eq!(map.len(), 5_000);
+        assert!(!map.is_empty());
+        assert_eq!(map.estimated_len(), 5_000);
+        for n in 0..10_000 {
+            map.remove(&n, &guard);
+        }
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_hashmap_concurrent_len() {
+        let nthreads = 8;
+        let num_keys = 10_000;
+        let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                spawn(move || {
+                    for i in 0..num_keys {
+                        let guard = map.guard();
+                        // Every thread puts all keys, and removes a third of them
+                        map.put(i, t, &guard);
+                        if i % 3 == 0 {
+                            map.remove(&i, &guard);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = shared_map.guard();
+        assert_eq!(shared_map.len(), shared_map.iter(&guard).count());
+    }
+
+    // Shared through a plain Arc, every thread sees what the others put
+    #[test]
+    fn test_hashmap_shared_through_arc() {
+        let shared_map = Arc::new(NonBlockingHashMap::<usize, usize>::new());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let map = Arc::clone(&shared_map);
+                spawn(move || {
+                    for i in 0..1000 {
+                        map.put(t * 1000 + i, i, &map.guard());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        for k in 0..4000 {
+            assert_eq!(shared_map.get_with(&k, |v| *v), Some(k % 1000));
+        }
+    }
 }
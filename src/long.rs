@@ -0,0 +1,360 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use crate::epoch::Guard;
+use crate::key::{is_boxed, is_tombstone, tombstone, Expected, ValueHolder};
+use crate::kvtable::KeySlots;
+use crate::{box_new_mut_ptr, is_match, value_of, MatchingTypes, PutOutcome, RawMap, MEMORY_ORDERING, MIN_SIZE};
+
+// ---Long Key Slots ---------------------------------------------------------------------------------
+// Keys kept in the slot words themselves, so no key is ever boxed. 0 marks an empty slot, which is
+// why the map keeps key 0 aside.
+#[derive(Debug)]
+pub struct LongKeys {
+    _ks: Vec<AtomicU64>,
+}
+
+// Any nonzero key will do. copy_slot() only puts it into a slot which was empty, so a real key
+// which is the same can't sit further along that probe sequence, and the slot's value is TombPrime
+// for good, which sends whoever matches it on to the new table.
+const KEY_TOMBSTONE: u64 = 1;
+
+impl KeySlots for LongKeys {
+    type Key = u64;
+    type Word = u64;
+
+    const EMPTY: u64 = 0;
+
+    fn new(table_size: usize) -> Self {
+        LongKeys {
+            _ks: (0..table_size).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self._ks.len()
+    }
+
+    fn load(&self, idx: usize) -> u64 {
+        self._ks[idx].load(Ordering::SeqCst)
+    }
+
+    fn cas(&self, idx: usize, old: u64, new: u64) -> u64 {
+        match self._ks[idx].compare_exchange(old, new, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(prev) | Err(prev) => prev,
+        }
+    }
+
+    fn tombstone() -> u64 {
+        KEY_TOMBSTONE
+    }
+
+    // It can't be told from a real key, which only means probing doesn't cut short at it
+    fn is_tombstone(_word: u64) -> bool {
+        false
+    }
+
+    unsafe fn key(word: &u64) -> &u64 {
+        word
+    }
+
+    // Keys are copied by value, so no table owns them
+    fn set_moved(&self, _idx: usize) {}
+
+    unsafe fn free(_word: u64) {}
+}
+
+// ---Long Hash Map ---------------------------------------------------------------------------------
+/// A map keyed by u64 which keeps its keys in the slots themselves, so unlike
+/// `NonBlockingHashMap<u64, V>` it never allocates for a key.
+#[derive(Debug)]
+pub struct NonBlockingHashMapLong<V, S = RandomState> {
+    _raw: RawMap<LongKeys, V, S>,
+    // Key 0 marks an empty slot in the tables, so its value is kept here instead
+    _zero: AtomicPtr<ValueHolder<V>>,
+}
+
+impl<V: Eq, S: BuildHasher + Default> Default for NonBlockingHashMapLong<V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMapLong;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<NonBlockingHashMapLong<std::rc::Rc<i32>>>();
+/// ```
+unsafe impl<V: Send + Sync, S: Send> Send for NonBlockingHashMapLong<V, S> {}
+
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashMapLong;
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<NonBlockingHashMapLong<std::cell::Cell<i32>>>();
+/// ```
+unsafe impl<V: Send + Sync, S: Sync> Sync for NonBlockingHashMapLong<V, S> {}
+
+impl<V, S> Drop for NonBlockingHashMapLong<V, S> {
+    fn drop(&mut self) {
+        let v = self._zero.load(Ordering::SeqCst);
+        if is_boxed(v) {
+            drop(unsafe { Box::from_raw(v) });
+        }
+    }
+}
+
+impl<V: Eq> NonBlockingHashMapLong<V> {
+    pub fn new() -> NonBlockingHashMapLong<V> {
+        NonBlockingHashMapLong::with_capacity(MIN_SIZE)
+    }
+
+    pub fn with_capacity(initial_sz: usize) -> NonBlockingHashMapLong<V> {
+        NonBlockingHashMapLong::with_capacity_and_hasher(initial_sz, RandomState::new())
+    }
+}
+
+impl<V: Eq, S: BuildHasher> NonBlockingHashMapLong<V, S> {
+    pub fn with_hasher(hash_builder: S) -> NonBlockingHashMapLong<V, S> {
+        NonBlockingHashMapLong::with_capacity_and_hasher(MIN_SIZE, hash_builder)
+    }
+
+    pub fn with_capacity_and_hasher(initial_sz: usize, hash_builder: S) -> NonBlockingHashMapLong<V, S> {
+        NonBlockingHashMapLong {
+            _raw: RawMap::with_capacity_and_hasher(initial_sz, hash_builder),
+            _zero: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Pins the map: nothing read from it is freed while the guard is held
+    pub fn guard(&self) -> Guard<'_> {
+        self._raw.guard()
+    }
+
+    pub fn put<'g>(&'g self, key: u64, newval: V, guard: &'g Guard) -> PutOutcome<'g, V> {
+        self._raw.check_guard(guard);
+        unsafe {
+            let putval = box_new_mut_ptr(ValueHolder::Value(newval));
+            PutOutcome::from_result(self.put_if_match(key, key, putval, MatchingTypes::MatchAll, None, guard))
+        }
+    }
+
+    pub fn remove<'g>(&'g self, key: u64, guard: &'g Guard) -> Option<&'g V> {
+        self._raw.check_guard(guard);
+        unsafe {
+            match self.put_if_match(key, LongKeys::EMPTY, tombstone(), MatchingTypes::MatchAll, None, guard) {
+                Ok(oldval) => value_of(oldval),
+                // The key isn't in the map at all
+                Err(_) => None,
+            }
+        }
+    }
+
+    // Puts only if there is no value for the key. Rejected with the value which won otherwise.
+    pub fn put_if_absent<'g>(&'g self, key: u64, newval: V, guard: &'g Guard) -> PutOutcome<'g, V> {
+        self._raw.check_guard(guard);
+        unsafe {
+            let putval = box_new_mut_ptr(ValueHolder::Value(newval));
+            let result = self.put_if_match(key, key, putval, MatchingTypes::MatchValue, Some(Expected::Absent), guard);
+            PutOutcome::from_result(result)
+        }
+    }
+
+    // Puts only if there is a value for the key already.
+    pub fn replace<'g>(&'g self, key: u64, newval: V, guard: &'g Guard) -> PutOutcome<'g, V> {
+        self._raw.check_guard(guard);
+        unsafe {
+            let putval = box_new_mut_ptr(ValueHolder::Value(newval));
+            let result = self.put_if_match(key, LongKeys::EMPTY, putval, MatchingTypes::MatchAllNotEmpty, None, guard);
+            PutOutcome::from_result(result)
+        }
+    }
+
+    // Puts only if the current value equals expval. Rejected with the current value otherwise.
+    pub fn compare_and_replace<'g>(&'g self, key: u64, expval: &V, newval: V, guard: &'g Guard) -> PutOutcome<'g, V> {
+        self._raw.check_guard(guard);
+        unsafe {
+            let putval = box_new_mut_ptr(ValueHolder::Value(newval));
+            let result = self.put_if_match(
+                key,
+                LongKeys::EMPTY,
+                putval,
+                MatchingTypes::MatchValue,
+                Some(Expected::Value(expval)),
+                guard,
+            );
+            PutOutcome::from_result(result)
+        }
+    }
+
+    /// The value stays readable as long as the guard is held, even if it's replaced or removed
+    /// in the meantime. The guard must come from this map.
+    pub fn get<'g>(&'g self, key: u64, guard: &'g Guard) -> Option<&'g V> {
+        self._raw.check_guard(guard);
+        if key == 0 {
+            return unsafe { value_of(self._zero.load(MEMORY_ORDERING)) };
+        }
+        let table = self._raw.get_table_nonatomic();
+        let maybe_val = unsafe { self._raw.get_impl(table, &key, guard) };
+        maybe_val.map(|v| unsafe { (*v).value() })
+    }
+
+    /// Calls f with the value for the key, if there's one, and returns what it returns
+    pub fn get_with<R, F: FnOnce(&V) -> R>(&self, key: u64, f: F) -> Option<R> {
+        let guard = self._raw.guard();
+        self.get(key, &guard).map(f)
+    }
+
+    pub fn contains_key(&self, key: u64) -> bool {
+        let guard = self._raw.guard();
+        self.get(key, &guard).is_some()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self._raw.capacity()
+    }
+
+    /// The number of keys with a value, key 0 included
+    pub fn len(&self) -> usize {
+        self._raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn estimated_len(&self) -> usize {
+        self._raw.estimated_len()
+    }
+
+    // Like RawMap::put_if_match(). keyword is the key if the put may add it, or EMPTY if not.
+    unsafe fn put_if_match(
+        &self,
+        key: u64,
+        keyword: u64,
+        putval: *mut ValueHolder<V>,
+        matchingtype: MatchingTypes,
+        expval: Option<Expected<V>>,
+        guard: &Guard,
+    ) -> Result<*mut ValueHolder<V>, *mut ValueHolder<V>> {
+        if key != 0 {
+            return self._raw.put_if_match(&key, keyword, putval, matchingtype, expval, guard);
+        }
+        // Key 0 has a single slot which is never copied, so there's only the value to swap
+        let result = loop {
+            let v = self._zero.load(MEMORY_ORDERING);
+            if !is_match(&matchingtype, expval.as_ref(), v) {
+                break Err(v);
+            }
+            if self._zero.compare_exchange(v, putval, MEMORY_ORDERING, MEMORY_ORDERING).is_ok() {
+                // Counted like any other key
+                match (value_of(v).is_some(), is_tombstone(putval)) {
+                    (false, false) => self._raw._size.add(1),
+                    (true, true) => self._raw._size.add(-1),
+                    _ => {}
+                }
+                break Ok(v);
+            }
+        };
+        match result {
+            Ok(oldval) if is_boxed(oldval) => guard.defer_drop(oldval),
+            Err(_) if is_boxed(putval) => drop(Box::from_raw(putval)),
+            _ => {}
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NonBlockingHashMapLong;
+    use crate::PutOutcome;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    #[test]
+    fn test_long_put_get_remove() {
+        let map = NonBlockingHashMapLong::new();
+        let guard = map.guard();
+        for key in [0, 1, 2, u64::MAX] {
+            assert_eq!(map.get(key, &guard), None);
+            assert_eq!(map.put(key, key.to_string(), &guard), PutOutcome::Inserted);
+            assert_eq!(map.put(key, format!("{}!", key), &guard), PutOutcome::Replaced(&key.to_string()));
+        }
+        assert_eq!(map.len(), 4);
+        for key in [0, 1, 2, u64::MAX] {
+            assert_eq!(map.get(key, &guard), Some(&format!("{}!", key)));
+            assert_eq!(map.remove(key, &guard), Some(&format!("{}!", key)));
+            assert_eq!(map.remove(key, &guard), None);
+            assert!(!map.contains_key(key));
+        }
+        assert_eq!(map.remove(3, &guard), None);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_long_conditional_puts() {
+        let map = NonBlockingHashMapLong::new();
+        let guard = map.guard();
+        for key in [0, 7] {
+            assert_eq!(map.replace(key, 1, &guard), PutOutcome::Rejected(None));
+            assert_eq!(map.put_if_absent(key, 2, &guard), PutOutcome::Inserted);
+            assert_eq!(map.put_if_absent(key, 3, &guard), PutOutcome::Rejected(Some(&2)));
+            assert_eq!(map.replace(key, 4, &guard), PutOutcome::Replaced(&2));
+            assert_eq!(map.compare_and_replace(key, &2, 5, &guard), PutOutcome::Rejected(Some(&4)));
+            assert_eq!(map.compare_and_replace(key, &4, 5, &guard), PutOutcome::Replaced(&4));
+            assert_eq!(map.get_with(key, |v| v * 10), Some(50));
+        }
+    }
+
+    #[test]
+    fn test_long_resize() {
+        let map = NonBlockingHashMapLong::with_capacity(16);
+        let guard = map.guard();
+        for i in 0..10_000u64 {
+            map.put(i, i * 3, &guard);
+        }
+        for i in (0..10_000u64).step_by(2) {
+            assert_eq!(map.remove(i, &guard), Some(&(i * 3)));
+        }
+        assert!(map.capacity() >= 10_000);
+        for i in 0..10_000u64 {
+            assert_eq!(map.get(i, &guard).copied(), if i % 2 == 0 { None } else { Some(i * 3) });
+        }
+        // Key 1 is also what emptied slots are tombstoned with
+        assert_eq!(map.put(1, 1, &guard), PutOutcome::Replaced(&3));
+        assert_eq!(map.get(1, &guard), Some(&1));
+    }
+
+    #[test]
+    fn test_long_concurrent() {
+        let nthreads = 4;
+        let num_keys = 10_000u64;
+        let map = Arc::new(NonBlockingHashMapLong::with_capacity(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = map.clone();
+                spawn(move || {
+                    let guard = map.guard();
+                    for i in t * num_keys..(t + 1) * num_keys {
+                        map.put(i, i, &guard);
+                    }
+                    for i in t * num_keys..(t + 1) * num_keys {
+                        assert_eq!(map.get(i, &guard), Some(&i));
+                        if i % 3 == 0 {
+                            assert_eq!(map.remove(i, &guard), Some(&i));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+        let guard = map.guard();
+        for i in 0..nthreads * num_keys {
+            assert_eq!(map.get(i, &guard), if i % 3 == 0 { None } else { Some(&i) });
+        }
+    }
+}
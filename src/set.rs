@@ -0,0 +1,269 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::ptr;
+
+use crate::epoch::Guard;
+use crate::key::{inline, tombstone, Expected, KeyHolder, ValueHolder};
+use crate::kvtable::BoxedKeys;
+use crate::{box_new_mut_ptr, MatchingTypes, RawIter, RawMap, MIN_SIZE};
+
+// What every value slot of a member holds. Inline, so it's never boxed.
+fn present() -> *mut ValueHolder<()> {
+    inline(0)
+}
+
+// ---Hash Set --------------------------------------------------------------------------------------
+/// A set whose members are the keys of a map with nothing in its value slots but an inline word,
+/// so only the members themselves are boxed.
+#[derive(Debug)]
+pub struct NonBlockingHashSet<T, S = RandomState> {
+    _raw: RawMap<BoxedKeys<T>, (), S>,
+}
+
+impl<T: Eq + Hash, S: BuildHasher + Default> Default for NonBlockingHashSet<T, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashSet;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<NonBlockingHashSet<std::rc::Rc<i32>>>();
+/// ```
+unsafe impl<T: Send + Sync, S: Send> Send for NonBlockingHashSet<T, S> {}
+
+/// ```compile_fail
+/// use nonblockinghashmap::NonBlockingHashSet;
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<NonBlockingHashSet<std::cell::RefCell<i32>>>();
+/// ```
+unsafe impl<T: Send + Sync, S: Sync> Sync for NonBlockingHashSet<T, S> {}
+
+impl<T: Eq + Hash> NonBlockingHashSet<T> {
+    pub fn new() -> NonBlockingHashSet<T> {
+        NonBlockingHashSet::with_capacity(MIN_SIZE)
+    }
+
+    pub fn with_capacity(initial_sz: usize) -> NonBlockingHashSet<T> {
+        NonBlockingHashSet::with_capacity_and_hasher(initial_sz, RandomState::new())
+    }
+}
+
+impl<T: Eq + Hash, S: BuildHasher> NonBlockingHashSet<T, S> {
+    pub fn with_hasher(hash_builder: S) -> NonBlockingHashSet<T, S> {
+        NonBlockingHashSet::with_capacity_and_hasher(MIN_SIZE, hash_builder)
+    }
+
+    pub fn with_capacity_and_hasher(initial_sz: usize, hash_builder: S) -> NonBlockingHashSet<T, S> {
+        NonBlockingHashSet {
+            _raw: RawMap::with_capacity_and_hasher(initial_sz, hash_builder),
+        }
+    }
+
+    /// Pins the set: no member read from it is freed while the guard is held
+    pub fn guard(&self) -> Guard<'_> {
+        self._raw.guard()
+    }
+
+    /// Returns true if the value wasn't a member yet
+    pub fn insert(&self, value: T) -> bool {
+        let guard = self._raw.guard();
+        unsafe {
+            let keyptr = box_new_mut_ptr(KeyHolder::Key(value));
+            // A member already in has the very same word, which put_if_match() gives up on at once
+            self._raw
+                .put_if_match(
+                    (*keyptr).key(),
+                    keyptr,
+                    present(),
+                    MatchingTypes::MatchValue,
+                    Some(Expected::Absent),
+                    &guard,
+                )
+                .is_ok()
+        }
+    }
+
+    /// Returns true if the value was a member
+    pub fn remove<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self._raw.guard();
+        let result = unsafe {
+            self._raw
+                .put_if_match(value, ptr::null_mut(), tombstone(), MatchingTypes::MatchAll, None, &guard)
+        };
+        result.is_ok_and(|oldval| oldval == present())
+    }
+
+    pub fn contains<Q>(&self, value: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let guard = self._raw.guard();
+        let table = self._raw.get_table_nonatomic();
+        unsafe { self._raw.get_impl(table, value, &guard) }.is_some()
+    }
+
+    /// Weakly consistent: every value which is a member for the whole walk is yielded once, and
+    /// values inserted or removed meanwhile may or may not be. The guard must come from this set.
+    pub fn iter<'g>(&'g self, guard: &'g Guard) -> SetIter<'g, T, S> {
+        self._raw.check_guard(guard);
+        SetIter {
+            _raw: unsafe { self._raw.iter(guard) },
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self._raw.capacity()
+    }
+
+    /// The number of members
+    pub fn len(&self) -> usize {
+        self._raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn estimated_len(&self) -> usize {
+        self._raw.estimated_len()
+    }
+
+    /// The members of either set, in a new set hashed like this one
+    pub fn union(&self, other: &NonBlockingHashSet<T, S>) -> NonBlockingHashSet<T, S>
+    where
+        T: Clone,
+        S: Clone,
+    {
+        let set = NonBlockingHashSet::with_hasher(self._raw._hasher.clone());
+        for value in self.iter(&self.guard()).chain(other.iter(&other.guard())) {
+            set.insert(value.clone());
+        }
+        set
+    }
+
+    /// The members of this set which are also in the other, in a new set hashed like this one
+    pub fn intersection(&self, other: &NonBlockingHashSet<T, S>) -> NonBlockingHashSet<T, S>
+    where
+        T: Clone,
+        S: Clone,
+    {
+        self.filtered(|value| other.contains(value))
+    }
+
+    /// The members of this set which aren't in the other, in a new set hashed like this one
+    pub fn difference(&self, other: &NonBlockingHashSet<T, S>) -> NonBlockingHashSet<T, S>
+    where
+        T: Clone,
+        S: Clone,
+    {
+        self.filtered(|value| !other.contains(value))
+    }
+
+    fn filtered<F: Fn(&T) -> bool>(&self, keep: F) -> NonBlockingHashSet<T, S>
+    where
+        T: Clone,
+        S: Clone,
+    {
+        let set = NonBlockingHashSet::with_hasher(self._raw._hasher.clone());
+        for value in self.iter(&self.guard()).filter(|value| keep(value)) {
+            set.insert(value.clone());
+        }
+        set
+    }
+}
+
+/// The members of a set, borrowed for as long as the guard is held
+pub struct SetIter<'g, T, S> {
+    _raw: RawIter<'g, BoxedKeys<T>, (), S>,
+}
+
+impl<'g, T: Eq + Hash, S: BuildHasher> Iterator for SetIter<'g, T, S> {
+    type Item = &'g T;
+
+    fn next(&mut self) -> Option<&'g T> {
+        self._raw.next().map(|(k, _)| unsafe { (*k).key() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NonBlockingHashSet;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread::spawn;
+
+    fn members(set: &NonBlockingHashSet<u64>) -> HashSet<u64> {
+        set.iter(&set.guard()).copied().collect()
+    }
+
+    #[test]
+    fn test_set_insert_contains_remove() {
+        let set = NonBlockingHashSet::<String>::new();
+        assert!(!set.contains("a"));
+        assert!(set.insert(String::from("a")));
+        assert!(!set.insert(String::from("a")));
+        assert!(set.contains("a"));
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert!(!set.contains("a"));
+        assert!(set.insert(String::from("a")));
+        assert_eq!(set.iter(&set.guard()).collect::<Vec<_>>(), vec!["a"]);
+    }
+
+    #[test]
+    fn test_set_iter_after_resize() {
+        let set = NonBlockingHashSet::with_capacity(16);
+        for i in 0..10_000 {
+            set.insert(i);
+        }
+        for i in (0..10_000).step_by(2) {
+            set.remove(&i);
+        }
+        assert!(set.capacity() >= 10_000);
+        assert_eq!(members(&set), (1..10_000).step_by(2).collect());
+        assert_eq!(set.iter(&set.guard()).count(), 5_000);
+        assert_eq!(set.len(), 5_000);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a = NonBlockingHashSet::new();
+        let b = NonBlockingHashSet::new();
+        for i in 0..100 {
+            a.insert(i);
+        }
+        for i in 50..150 {
+            b.insert(i);
+        }
+        assert_eq!(members(&a.union(&b)), (0..150).collect());
+        assert_eq!(members(&a.intersection(&b)), (50..100).collect());
+        assert_eq!(members(&a.difference(&b)), (0..50).collect());
+        assert_eq!(members(&b.difference(&a)), (100..150).collect());
+        assert_eq!(members(&a.intersection(&NonBlockingHashSet::new())), HashSet::new());
+    }
+
+    // Each member is inserted by exactly one of the threads racing to insert it
+    #[test]
+    fn test_set_concurrent_insert() {
+        let nthreads = 4;
+        let num_values = 10_000;
+        let set = Arc::new(NonBlockingHashSet::with_capacity(16));
+        let threads: Vec<_> = (0..nthreads)
+            .map(|_| {
+                let set = set.clone();
+                spawn(move || (0..num_values).filter(|i| set.insert(*i)).count())
+            })
+            .collect();
+        let inserted: usize = threads.into_iter().map(|t| t.join().expect("Error joining")).sum();
+        assert_eq!(inserted, num_values as usize);
+        assert_eq!(members(&set), (0..num_values).collect());
+    }
+}
@@ -0,0 +1,181 @@
+// Checks which operations allocate.
+
+mod common;
+
+use common::allocations;
+use nonblockinghashmap::{CounterMap, InlineValueMap, NonBlockingHashMap, NonBlockingHashMapLong, NonBlockingHashSet};
+
+#[test]
+fn borrowed_lookups_do_not_allocate() {
+    let map = NonBlockingHashMap::<String, String>::with_capacity(100);
+    for i in 0..100 {
+        map.put(format!("key {}", i), format!("value {}", i), &map.guard());
+    }
+    let keys: Vec<String> = (0..200).map(|i| format!("key {}", i)).collect();
+    let guard = map.guard();
+    // A second pin slot for the operations which pin on their own
+    drop(map.guard());
+
+    let before = allocations();
+    for (i, key) in keys.iter().enumerate() {
+        let key: &str = key;
+        assert_eq!(map.get(key, &guard).is_some(), i < 100);
+        assert_eq!(map.contains_key(key), i < 100);
+        assert_eq!(map.get_with(key, |v| v.len()).is_some(), i < 100);
+    }
+    assert_eq!(allocations(), before);
+}
+
+#[test]
+fn resizing_moves_entries_without_allocating() {
+    let num_keys = 1 << 16;
+    let map = NonBlockingHashMap::<u64, u64>::with_capacity(16);
+    let guard = map.guard();
+
+    let before = allocations();
+    for i in 0..num_keys {
+        map.put(i, i, &guard);
+    }
+    // One key and one value box per put. Everything else is per table, and there are few of those.
+    let per_entry = 2 * num_keys as usize;
+    assert!(map.capacity() >= num_keys as usize);
+    assert!(allocations() - before < per_entry + 1000, "{} allocations", allocations() - before);
+    for i in 0..num_keys {
+        assert_eq!(map.get(&i, &guard), Some(&i));
+    }
+}
+
+#[test]
+fn removing_missing_keys_does_not_allocate() {
+    let map = NonBlockingHashMap::<u64, u64>::with_capacity(1000);
+    for i in 0..100 {
+        map.put(i, i, &map.guard());
+    }
+    for i in 0..100 {
+        map.remove(&i, &map.guard());
+    }
+    let guard = map.guard();
+
+    // Neither keys which were removed already nor keys which were never in need a tombstone box
+    let before = allocations();
+    for i in 0..200 {
+        assert_eq!(map.remove(&i, &guard), None);
+    }
+    assert_eq!(allocations(), before);
+}
+
+#[test]
+fn inline_values_do_not_allocate() {
+    let map = InlineValueMap::<u64, u32>::with_capacity(1000);
+    for i in 0..100 {
+        map.put(i, 0);
+    }
+    // The pin slot the operations take
+    assert_eq!(map.get(&0), Some(0));
+
+    // Only a put which brings in a key boxes it
+    let before = allocations();
+    for round in 0..10 {
+        for i in 0..100 {
+            assert_eq!(map.replace(&i, round + 1), Some(round));
+            assert_eq!(map.compare_and_replace(&i, round + 1, round + 1), Ok(round + 1));
+            assert_eq!(map.get(&i), Some(round + 1));
+        }
+    }
+    for i in 0..100 {
+        assert_eq!(map.remove(&i), Some(10));
+    }
+    assert_eq!(allocations(), before);
+}
+
+#[test]
+fn long_keys_are_not_boxed() {
+    let num_keys = 1 << 16;
+    let map = NonBlockingHashMapLong::<u64>::with_capacity(16);
+    let guard = map.guard();
+
+    let before = allocations();
+    for i in 0..num_keys {
+        map.put(i, i, &guard);
+    }
+    // Only a value box per put, key 0 included
+    assert!(allocations() - before < num_keys as usize + 1000, "{} allocations", allocations() - before);
+}
+
+#[test]
+fn long_key_lookups_do_not_allocate() {
+    let map = NonBlockingHashMapLong::<u64>::with_capacity(1000);
+    for i in 0..100 {
+        map.put(i, i, &map.guard());
+    }
+    let guard = map.guard();
+
+    let before = allocations();
+    for i in 0..200 {
+        assert_eq!(map.get(i, &guard).is_some(), i < 100);
+    }
+    for i in 100..200 {
+        assert_eq!(map.remove(i, &guard), None);
+    }
+    assert_eq!(allocations(), before);
+}
+
+#[test]
+fn set_members_have_no_value_boxes() {
+    let num_values = 1 << 16;
+    let set = NonBlockingHashSet::<u64>::with_capacity(16);
+    // The pin slot the operations take
+    assert!(!set.contains(&0));
+
+    let before = allocations();
+    for i in 0..num_values {
+        assert!(set.insert(i));
+    }
+    // Only a key box per member
+    assert!(allocations() - before < num_values as usize + 1000, "{} allocations", allocations() - before);
+}
+
+#[test]
+fn counter_adds_do_not_allocate() {
+    let map = CounterMap::<String>::with_capacity(16);
+    let keys: Vec<String> = (0..1000).map(|i| format!("key {}", i)).collect();
+    for key in &keys {
+        map.add(key, 1);
+    }
+
+    // Only the first add to a key boxes the key and its count
+    let before = allocations();
+    for round in 0..10 {
+        for key in &keys {
+            assert_eq!(map.add(key, 1), round + 2);
+            assert_eq!(map.get(key.as_str()), round + 2);
+        }
+    }
+    assert_eq!(allocations(), before);
+}
+
+#[test]
+fn bulk_loading_allocates_only_entries_and_tables() {
+    let num_keys = 1 << 16;
+
+    let before = allocations();
+    let map: NonBlockingHashMap<u64, u64> = (0..num_keys).map(|i| (i, i)).collect();
+    // A key and a value box per entry, and a single table made to size
+    assert!(allocations() - before < 2 * num_keys as usize + 100, "{} allocations", allocations() - before);
+    assert_eq!(map.len(), num_keys as usize);
+}
+
+#[test]
+fn into_iter_moves_entries_without_allocating() {
+    let map = NonBlockingHashMap::<String, String>::with_capacity(1000);
+    for i in 0..1000 {
+        map.put(format!("key {}", i), format!("value {}", i), &map.guard());
+    }
+    let mut entries = Vec::with_capacity(1000);
+
+    // Keys and values come out of their boxes as they are, without a clone
+    let before = allocations();
+    entries.extend(map);
+    assert_eq!(allocations(), before);
+    assert_eq!(entries.len(), 1000);
+}
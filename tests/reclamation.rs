@@ -0,0 +1,120 @@
+// Checks that the map gives back whatever it retires.
+
+mod common;
+
+use common::{live_bytes, serial};
+use nonblockinghashmap::NonBlockingHashMap;
+use std::sync::Arc;
+use std::thread::spawn;
+
+// Room for the test harness's own allocations in between
+const SLACK: isize = 16 * 1024;
+
+fn churn(map: &NonBlockingHashMap<usize, String>, keys: std::ops::Range<usize>, round: usize) {
+    for i in keys.clone() {
+        map.put(i, format!("value {} {}", i, round), &map.guard());
+    }
+    for i in keys.clone() {
+        map.replace(&i, format!("replaced {} {}", i, round), &map.guard());
+    }
+    for i in keys.step_by(2) {
+        map.remove(&i, &map.guard());
+    }
+}
+
+#[test]
+fn steady_state_under_single_thread_churn() {
+    let _serial = serial();
+    let before = live_bytes();
+    let map = NonBlockingHashMap::with_capacity(16);
+    for round in 0..10 {
+        churn(&map, 0..10_000, round);
+    }
+    let warm = live_bytes();
+    for round in 10..100 {
+        churn(&map, 0..10_000, round);
+    }
+    assert!(live_bytes() <= warm + SLACK, "grew from {} to {}", warm, live_bytes());
+    drop(map);
+    assert!(live_bytes() <= before + SLACK, "{} bytes left", live_bytes() - before);
+}
+
+#[test]
+fn steady_state_under_concurrent_churn() {
+    let _serial = serial();
+    let nthreads = 4;
+    let num_keys = 5_000;
+    let before = live_bytes();
+    let shared_map = Arc::new(NonBlockingHashMap::with_capacity(16));
+
+    let run = |rounds: std::ops::Range<usize>| {
+        let threads: Vec<_> = (0..nthreads)
+            .map(|t| {
+                let map = shared_map.clone();
+                let rounds = rounds.clone();
+                spawn(move || {
+                    for round in rounds {
+                        churn(&map, t * num_keys..(t + 1) * num_keys, round);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Error joining");
+        }
+    };
+    run(0..10);
+    let warm = live_bytes();
+    run(10..60);
+    assert!(live_bytes() <= warm + SLACK, "grew from {} to {}", warm, live_bytes());
+    drop(shared_map);
+    assert!(live_bytes() <= before + SLACK, "{} bytes left", live_bytes() - before);
+}
+
+#[test]
+fn growing_map_frees_every_table() {
+    let _serial = serial();
+    let before = live_bytes();
+    let map = NonBlockingHashMap::with_capacity(16);
+    for i in 0..100_000 {
+        map.put(i, format!("value {}", i), &map.guard());
+    }
+    assert!(map.capacity() >= 100_000);
+    drop(map);
+    assert!(live_bytes() <= before + SLACK, "{} bytes left", live_bytes() - before);
+}
+
+#[test]
+fn extending_map_frees_replaced_tables_and_values() {
+    let _serial = serial();
+    let before = live_bytes();
+    let mut map = NonBlockingHashMap::with_capacity(16);
+    for i in 0..1000 {
+        map.put(i, format!("value {}", i), &map.guard());
+    }
+    for i in (0..1000).step_by(2) {
+        map.remove(&i, &map.guard());
+    }
+    // Without a size hint the table grows as it's filled
+    map.extend((0..100_000).filter(|i| i % 3 == 0).map(|i| (i, format!("extended {}", i))));
+    assert!(map.capacity() >= 100_000);
+    drop(map);
+    assert!(live_bytes() <= before + SLACK, "{} bytes left", live_bytes() - before);
+}
+
+#[test]
+fn into_iter_frees_whatever_is_left() {
+    let _serial = serial();
+    let before = live_bytes();
+    let map = NonBlockingHashMap::with_capacity(16);
+    for i in 0..10_000 {
+        map.put(i, format!("value {}", i), &map.guard());
+    }
+    for i in (0..10_000).step_by(2) {
+        map.remove(&i, &map.guard());
+    }
+    let taken: Vec<(usize, String)> = map.into_iter().take(1000).collect();
+    assert_eq!(taken.len(), 1000);
+    drop(taken);
+    assert!(live_bytes() <= before + SLACK, "{} bytes left", live_bytes() - before);
+}